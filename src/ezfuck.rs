@@ -1,3 +1,5 @@
 pub mod parser;
 pub mod interpreter;
-pub mod repl;
\ No newline at end of file
+pub mod repl;
+pub mod dap;
+pub mod tui;
\ No newline at end of file
@@ -0,0 +1,2 @@
+pub mod dap;
+pub mod protocol;
\ No newline at end of file
@@ -0,0 +1,751 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use serde_json::{json, Value};
+
+use crate::ezfuck::dap::protocol::{read_message, write_message};
+use crate::ezfuck::interpreter::errors::catch_error;
+use crate::ezfuck::interpreter::interpreter::{interpret_instruction, ExecutionState};
+use crate::ezfuck::parser::parser::{compile_with_source_map, produce_cell_name, CompileOptions, EqualityOperator, Instruction, SourceMap, SourcePosition};
+use crate::program_io::program_io::MemoryIo;
+
+const THREAD_ID: i64 = 1;
+const TAPE_VARIABLES_REFERENCE: i64 = 1;
+const POINTERS_VARIABLES_REFERENCE: i64 = 2;
+
+// How many instructions are executed between checks for incoming requests (like "pause").
+const INSTRUCTIONS_PER_SLICE: usize = 10_000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum RunMode {
+    Continue,
+    Step,
+    // Runs until a call returns from below call_depth, or until the instruction pointer leaves start..=end while the
+    //  return stack is back at call_depth. Without a range, only returning stops it.
+    StepOutOf { call_depth: usize, range: Option<(usize, usize)> },
+}
+
+struct DebugSession<W: Write> {
+    out_stream: W,
+    seq: i64,
+    program_path: String,
+    instructions: Vec<Instruction>,
+    source_map: SourceMap,
+    // The lines that breakpoints were requested on, by the path of the file they're in.
+    breakpoint_lines: HashMap<String, Vec<usize>>,
+    breakpoints: HashSet<usize>,
+    state: ExecutionState,
+    input: Vec<u8>,
+    // Printed bytes that haven't been sent to the client yet, like the first bytes of a character that's still being
+    //  printed.
+    output: Vec<u8>,
+    is_launched: bool,
+    is_configured: bool,
+    stop_on_entry: bool,
+    run_mode: Option<RunMode>,
+    has_executed_since_resume: bool,
+    is_terminated: bool,
+}
+
+impl<W: Write> DebugSession<W> {
+    fn new(out_stream: W) -> DebugSession<W> {
+        return DebugSession {
+            out_stream,
+            seq: 1,
+            program_path: String::new(),
+            instructions: vec![],
+            source_map: SourceMap { sources: vec![], positions: vec![] },
+            breakpoint_lines: HashMap::new(),
+            breakpoints: HashSet::new(),
+            // History isn't needed, and it would make the input position no longer map directly into `input`.
+            state: ExecutionState::with_history_size(0),
+            input: vec![],
+            output: vec![],
+            is_launched: false,
+            is_configured: false,
+            stop_on_entry: false,
+            run_mode: None,
+            has_executed_since_resume: false,
+            is_terminated: false,
+        };
+    }
+
+    fn is_running(self: &Self) -> bool {
+        return self.run_mode.is_some();
+    }
+
+    fn send(self: &mut Self, mut message: Value) -> () {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        write_message(&mut self.out_stream, &message);
+    }
+
+    fn send_event(self: &mut Self, event: &str, body: Value) -> () {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn send_response(self: &mut Self, request: &Value, body: Value) -> () {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn send_error_response(self: &mut Self, request: &Value, error_message: &str) -> () {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": error_message,
+        }));
+    }
+
+    fn load_program(self: &mut Self, program_path: &str, code: &str, options: CompileOptions) -> Result<(), String> {
+        let (instructions, source_map) = catch_error(|| compile_with_source_map(code, Some(Path::new(program_path)), &options))?;
+        self.program_path = program_path.to_string();
+        self.instructions = instructions;
+        self.source_map = source_map;
+        self.resolve_breakpoints();
+        return Ok(());
+    }
+
+    // Paths are compared after being made canonical, since the client and the include may refer to the same file
+    //  differently. Breakpoints without a path are in the program itself.
+    fn find_source_index(self: &Self, path: &str) -> Option<usize> {
+        if path.is_empty() {
+            return Some(0);
+        }
+
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let requested_path = canonical(Path::new(path));
+        return self.source_map.sources.iter().position(|source| source.path.as_deref().map(canonical).as_ref() == Some(&requested_path));
+    }
+
+    // Breakpoints are set on lines, and stop at the first instruction compiled from that line. A line in a macro is
+    //  compiled once for each use of the macro, so there can be more than one of these.
+    fn find_breakpoint_instructions(self: &Self, path: &str, line: usize) -> Vec<usize> {
+        let file = match self.find_source_index(path) {
+            Some(file) => file,
+            None => return vec![],
+        };
+
+        let is_on_line = |i: usize| self.source_map.positions.get(i).is_some_and(|position| position.file == file && position.line == line);
+        return (0..self.source_map.positions.len())
+            .filter(|i| is_on_line(*i) && (*i == 0 || !is_on_line(i - 1)))
+            .collect();
+    }
+
+    fn resolve_breakpoints(self: &mut Self) -> () {
+        self.breakpoints = self.breakpoint_lines.iter()
+            .flat_map(|(path, lines)| lines.iter().flat_map(|line| self.find_breakpoint_instructions(path, *line)))
+            .collect();
+    }
+
+    fn describe_breakpoints(self: &Self, path: &str) -> Vec<Value> {
+        let lines = self.breakpoint_lines.get(path).cloned().unwrap_or(vec![]);
+        return lines.into_iter().map(|line| {
+            if !self.find_breakpoint_instructions(path, line).is_empty() {
+                json!({ "verified": true, "line": line })
+            } else {
+                json!({ "verified": false, "line": line, "message": "No instructions on this line" })
+            }
+        }).collect();
+    }
+
+    fn find_enclosing_loop(self: &Self, instruction_i: usize) -> Option<(usize, usize)> {
+        let mut enclosing: Option<(usize, usize)> = None;
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            if let Instruction::JumpToIf { position, operator: EqualityOperator::Equal, .. } = instruction {
+                let contains_instruction = i < instruction_i && instruction_i <= *position;
+                let is_innermost = enclosing.map_or(true, |(start, _)| start < i);
+                if contains_instruction && is_innermost {
+                    enclosing = Some((i, *position));
+                }
+            }
+        }
+
+        return enclosing;
+    }
+
+    fn find_instruction_source_position(self: &Self, instruction_ptr: usize) -> SourcePosition {
+        let last_position = self.source_map.positions.last().copied().unwrap_or(SourcePosition { file: 0, line: 1, column: 1 });
+        return self.source_map.positions.get(instruction_ptr).copied().unwrap_or(last_position);
+    }
+
+    fn start_execution(self: &mut Self) -> () {
+        if !(self.is_launched && self.is_configured) || self.is_terminated {
+            return;
+        }
+
+        if self.stop_on_entry {
+            self.stop("entry");
+        } else {
+            self.resume(RunMode::Continue);
+        }
+    }
+
+    fn resume(self: &mut Self, run_mode: RunMode) -> () {
+        self.run_mode = Some(run_mode);
+        self.has_executed_since_resume = false;
+    }
+
+    fn stop(self: &mut Self, reason: &str) -> () {
+        self.run_mode = None;
+        self.send_event("stopped", json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }));
+    }
+
+    fn terminate(self: &mut Self, exit_code: i64) -> () {
+        self.run_mode = None;
+        if !self.is_terminated {
+            self.flush_program_output(true);
+            self.is_terminated = true;
+            self.send_event("exited", json!({ "exitCode": exit_code }));
+            self.send_event("terminated", json!({}));
+        }
+    }
+
+    // Ends the session the way a crashing program would, with the error shown in the client's console.
+    fn fail(self: &mut Self, message: &str) -> () {
+        self.flush_program_output(true);
+        self.send_event("output", json!({ "category": "stderr", "output": format!("{message}\n") }));
+        self.terminate(1);
+    }
+
+    // Output is sent as text, so a character whose bytes are printed by separate instructions is held back until all of
+    //  them have been printed. Once the program ends, whatever is left is sent even if it isn't valid UTF-8.
+    fn flush_program_output(self: &mut Self, is_ending: bool) -> () {
+        let send_len = if is_ending { self.output.len() } else { find_complete_utf8_len(&self.output) };
+        if send_len > 0 {
+            let text = String::from_utf8_lossy(&self.output[..send_len]).to_string();
+            self.output.drain(..send_len);
+            self.send_event("output", json!({ "category": "stdout", "output": text }));
+        }
+    }
+
+    // Returns false if the program could not continue, after ending the session.
+    fn execute_current_instruction(self: &mut Self) -> bool {
+        let instruction = self.instructions[self.state.instruction_ptr];
+        // A byte that was put back by a read is replayed from the state's input history, so it isn't offered again here.
+        let read_len = self.state.recorded_input_end().max(self.state.input_position);
+        let remaining_input = &self.input[read_len.min(self.input.len())..];
+
+        let mut io = MemoryIo::new(remaining_input);
+        let result = catch_error(|| interpret_instruction(instruction, &mut self.state, &mut io, true));
+        self.output.extend(io.output);
+        if let Err(message) = result {
+            self.fail(&message);
+            return false;
+        }
+
+        self.state.instruction_ptr += 1;
+        self.flush_program_output(false);
+
+        return true;
+    }
+
+    fn run_slice(self: &mut Self) -> () {
+        for _ in 0..INSTRUCTIONS_PER_SLICE {
+            let run_mode = match self.run_mode {
+                Some(run_mode) => run_mode,
+                None => return,
+            };
+
+            if self.state.instruction_ptr >= self.instructions.len() {
+                self.terminate(0);
+                return;
+            }
+
+            if self.has_executed_since_resume && self.breakpoints.contains(&self.state.instruction_ptr) {
+                self.stop("breakpoint");
+                return;
+            }
+
+            if !self.execute_current_instruction() {
+                return;
+            }
+            self.has_executed_since_resume = true;
+
+            if self.state.is_debugging {
+                // A "!" in the source acts as a breakpoint.
+                self.state.is_debugging = false;
+                self.stop("breakpoint");
+                return;
+            }
+
+            let should_stop = match run_mode {
+                RunMode::Continue => false,
+                RunMode::Step => true,
+                RunMode::StepOutOf { call_depth, range } => {
+                    let depth = self.state.call_stack.len();
+                    let is_outside_range = range.is_some_and(|(start, end)| self.state.instruction_ptr < start || self.state.instruction_ptr > end);
+                    depth < call_depth || (depth == call_depth && is_outside_range)
+                },
+            };
+
+            if should_stop {
+                if self.state.instruction_ptr >= self.instructions.len() {
+                    self.terminate(0);
+                } else {
+                    self.stop("step");
+                }
+                return;
+            }
+        }
+    }
+
+    fn handle_launch(self: &mut Self, request: &Value) -> () {
+        let arguments = &request["arguments"];
+        let program_path = match arguments["program"].as_str() {
+            Some(path) => path.to_string(),
+            None => {
+                self.send_error_response(request, "A \"program\" path must be given");
+                return;
+            }
+        };
+
+        let code = match std::fs::read_to_string(&program_path) {
+            Ok(code) => code,
+            Err(err) => {
+                self.send_error_response(request, &format!("Could not read file: {err}"));
+                return;
+            }
+        };
+
+        let mut options = CompileOptions::new(true);
+        options.is_strict = arguments["strict"].as_bool().unwrap_or(false);
+        options.allow_numeric_io = arguments["numericIo"].as_bool().unwrap_or(false);
+        options.include_paths = arguments["includePaths"].as_array().cloned().unwrap_or(vec![]).iter()
+            .filter_map(|path| path.as_str())
+            .map(PathBuf::from)
+            .collect();
+
+        if let Err(message) = self.load_program(&program_path, &code, options) {
+            self.send_error_response(request, &message);
+            return;
+        }
+
+        self.input = arguments["input"].as_str().unwrap_or("").as_bytes().to_vec();
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.is_launched = true;
+
+        self.send_response(request, json!({}));
+        self.start_execution();
+    }
+
+    fn handle_set_breakpoints(self: &mut Self, request: &Value) -> () {
+        let path = request["arguments"]["source"]["path"].as_str().unwrap_or("").to_string();
+        let requested = request["arguments"]["breakpoints"].as_array().cloned().unwrap_or(vec![]);
+        let lines = requested.iter()
+            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+            .map(|line| line as usize)
+            .collect();
+        self.breakpoint_lines.insert(path.clone(), lines);
+
+        self.resolve_breakpoints();
+        let resolved = self.describe_breakpoints(&path);
+        self.send_response(request, json!({ "breakpoints": resolved }));
+    }
+
+    fn produce_stack_frame(self: &Self, id: usize, instruction_ptr: usize) -> Value {
+        let position = self.find_instruction_source_position(instruction_ptr);
+        let name = match self.instructions.get(instruction_ptr) {
+            Some(instruction) => format!("{instruction:?}"),
+            None => "End of program".to_string(),
+        };
+
+        // Instructions from included files are shown in those files.
+        let path = match self.source_map.sources.get(position.file).and_then(|source| source.path.as_ref()) {
+            Some(path) => path.display().to_string(),
+            None => self.program_path.clone(),
+        };
+
+        return json!({
+            "id": id,
+            "name": name,
+            "source": { "path": path },
+            "line": position.line,
+            "column": position.column,
+        });
+    }
+
+    // Each call that hasn't returned yet gets its own frame, below the frame of the current instruction.
+    fn handle_stack_trace(self: &mut Self, request: &Value) -> () {
+        let mut frames = vec![self.produce_stack_frame(0, self.state.instruction_ptr)];
+        for (i, call_position) in self.state.call_stack.iter().rev().enumerate() {
+            frames.push(self.produce_stack_frame(i + 1, *call_position));
+        }
+
+        let n_frames = frames.len();
+        self.send_response(request, json!({ "stackFrames": frames, "totalFrames": n_frames }));
+    }
+
+    fn handle_variables(self: &mut Self, request: &Value) -> () {
+        let variables: Vec<Value> = match request["arguments"]["variablesReference"].as_i64() {
+            Some(TAPE_VARIABLES_REFERENCE) => {
+                self.state.cells.iter().enumerate().map(|(i, cell)| {
+                    let marker = if i == self.state.cell_ptr { " <" } else { "" };
+                    json!({ "name": format!("[{i}] {}", produce_cell_name(i)), "value": format!("{cell}{marker}"), "variablesReference": 0 })
+                }).collect()
+            },
+            Some(POINTERS_VARIABLES_REFERENCE) => {
+                vec![
+                    json!({ "name": "cell_ptr", "value": self.state.cell_ptr.to_string(), "variablesReference": 0 }),
+                    json!({ "name": "instruction_ptr", "value": self.state.instruction_ptr.to_string(), "variablesReference": 0 }),
+                ]
+            },
+            _ => vec![],
+        };
+
+        self.send_response(request, json!({ "variables": variables }));
+    }
+
+    fn handle_step(self: &mut Self, request: &Value, is_step_over: bool) -> () {
+        self.send_response(request, json!({}));
+
+        // Stepping over a call runs until it returns, and stepping over a loop runs until it's left.
+        let call_depth = self.state.call_stack.len();
+        let run_mode = match self.instructions.get(self.state.instruction_ptr) {
+            _ if !is_step_over => RunMode::Step,
+            Some(Instruction::JumpToIf { position, operator: EqualityOperator::Equal, .. }) => {
+                RunMode::StepOutOf { call_depth, range: Some((self.state.instruction_ptr, *position)) }
+            },
+            _ => RunMode::StepOutOf { call_depth, range: Some((self.state.instruction_ptr, self.state.instruction_ptr)) },
+        };
+
+        self.resume(run_mode);
+    }
+
+    fn handle_step_out(self: &mut Self, request: &Value) -> () {
+        self.send_response(request, json!({}));
+
+        // Steps out of the innermost loop if there is one, or else out of the current call.
+        let call_depth = self.state.call_stack.len();
+        let run_mode = match self.find_enclosing_loop(self.state.instruction_ptr) {
+            Some(range) => RunMode::StepOutOf { call_depth, range: Some(range) },
+            None if call_depth > 0 => RunMode::StepOutOf { call_depth, range: None },
+            None => RunMode::Continue,
+        };
+
+        self.resume(run_mode);
+    }
+
+    // Returns false once the session should end.
+    fn handle_request(self: &mut Self, request: &Value) -> bool {
+        let command = request["command"].as_str().unwrap_or("").to_string();
+        match command.as_str() {
+            "initialize" => {
+                self.send_response(request, json!({ "supportsConfigurationDoneRequest": true }));
+                self.send_event("initialized", json!({}));
+            },
+            "launch" => self.handle_launch(request),
+            "setBreakpoints" => self.handle_set_breakpoints(request),
+            "configurationDone" => {
+                self.send_response(request, json!({}));
+                self.is_configured = true;
+                self.start_execution();
+            },
+            "threads" => {
+                self.send_response(request, json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }));
+            },
+            "stackTrace" => self.handle_stack_trace(request),
+            "scopes" => {
+                let scopes = json!([
+                    { "name": "Tape", "variablesReference": TAPE_VARIABLES_REFERENCE, "expensive": false },
+                    { "name": "Pointers", "variablesReference": POINTERS_VARIABLES_REFERENCE, "expensive": false },
+                ]);
+                self.send_response(request, json!({ "scopes": scopes }));
+            },
+            "variables" => self.handle_variables(request),
+            "continue" => {
+                self.send_response(request, json!({ "allThreadsContinued": true }));
+                self.resume(RunMode::Continue);
+            },
+            "next" => self.handle_step(request, true),
+            "stepIn" => self.handle_step(request, false),
+            "stepOut" => self.handle_step_out(request),
+            "pause" => {
+                self.send_response(request, json!({}));
+                if self.is_running() {
+                    self.stop("pause");
+                }
+            },
+            "disconnect" | "terminate" => {
+                self.send_response(request, json!({}));
+                self.terminate(0);
+                return false;
+            },
+            _ => {
+                self.send_error_response(request, &format!("Unsupported request: {command}"));
+            },
+        }
+
+        return true;
+    }
+
+    fn run(self: &mut Self, requests: Receiver<Value>) -> () {
+        loop {
+            let request = if self.is_running() {
+                match requests.try_recv() {
+                    Ok(request) => request,
+                    Err(TryRecvError::Empty) => {
+                        self.run_slice();
+                        continue;
+                    },
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => return,
+                }
+            };
+
+            if !self.handle_request(&request) {
+                return;
+            }
+        }
+    }
+}
+
+// How many of the bytes can be decoded already. Invalid bytes count, since they'll be replaced either way, but a
+//  character at the end that more bytes could still complete doesn't.
+fn find_complete_utf8_len(bytes: &[u8]) -> usize {
+    let mut decoded_len = 0;
+    loop {
+        let error = match std::str::from_utf8(&bytes[decoded_len..]) {
+            Ok(_) => return bytes.len(),
+            Err(error) => error,
+        };
+
+        match error.error_len() {
+            Some(invalid_len) => decoded_len += error.valid_up_to() + invalid_len,
+            None => return decoded_len + error.valid_up_to(),
+        }
+    }
+}
+
+pub fn start_dap_server() -> () {
+    let (sender, receiver) = channel();
+
+    // Requests are read on a separate thread so that "pause" can be received while the program is running.
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        while let Some(message) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = DebugSession::new(io::stdout());
+    session.run(receiver);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ezfuck::dap::protocol::read_message;
+    use super::*;
+
+    fn collect_messages(session: &DebugSession<Vec<u8>>) -> Vec<Value> {
+        let mut in_stream = &session.out_stream[..];
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut in_stream) {
+            messages.push(message);
+        }
+
+        return messages;
+    }
+
+    fn request(command: &str, arguments: Value) -> Value {
+        return json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments });
+    }
+
+    fn start_session(code: &str) -> DebugSession<Vec<u8>> {
+        let mut session = DebugSession::new(vec![]);
+        session.load_program("test.ez", code, CompileOptions::new(true)).unwrap();
+        session.is_launched = true;
+        return session;
+    }
+
+    #[test]
+    fn it_should_stop_on_a_breakpoint_and_report_its_line() {
+        let mut session = start_session("+2\n.\n+3");
+        session.handle_request(&request("setBreakpoints", json!({ "breakpoints": [{ "line": 3 }] })));
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+
+        assert_eq!(session.state.instruction_ptr, 2);
+        assert_eq!(session.find_instruction_source_position(session.state.instruction_ptr).line, 3);
+
+        let messages = collect_messages(&session);
+        let events: Vec<&str> = messages.iter().filter_map(|message| message["event"].as_str()).collect();
+        assert_eq!(events, vec!["output", "stopped"]);
+    }
+
+    #[test]
+    fn it_should_step_over_an_entire_loop() {
+        let mut session = start_session("+3[-]+");
+        session.stop_on_entry = true;
+        session.handle_request(&request("configurationDone", json!({})));
+        session.handle_request(&request("stepIn", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 1);
+
+        session.handle_request(&request("next", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 4);
+        assert_eq!(session.state.get_current_cell(), 0);
+    }
+
+    #[test]
+    fn it_should_step_out_of_the_enclosing_loop() {
+        let mut session = start_session("+3[->+<]+");
+        session.breakpoints = HashSet::from([4]);
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 4);
+
+        session.breakpoints.clear();
+        session.handle_request(&request("stepOut", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 7);
+        assert_eq!(session.state.cells, vec![0, 3]);
+    }
+
+    #[test]
+    fn it_should_step_over_a_call() {
+        let mut session = start_session("{>main} {sub} + [-] +2 {}\n{main}\n{+sub} +");
+        session.breakpoints = HashSet::from([9]);
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 9);
+
+        session.breakpoints.clear();
+        session.handle_request(&request("next", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 10);
+        assert!(session.state.call_stack.is_empty());
+        assert_eq!(session.state.get_current_cell(), 2);
+    }
+
+    #[test]
+    fn it_should_step_out_of_the_current_call() {
+        let mut session = start_session("{>main} {sub} + [-] +2 {}\n{main}\n{+sub} +");
+        session.breakpoints = HashSet::from([2]);
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 2);
+        assert_eq!(session.state.call_stack.len(), 1);
+
+        session.breakpoints.clear();
+        session.handle_request(&request("stepOut", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 10);
+        assert!(session.state.call_stack.is_empty());
+    }
+
+    #[test]
+    fn it_should_report_a_frame_for_each_unreturned_call() {
+        let mut session = start_session("{>main} {sub} + {}\n{main}\n{+sub}");
+        session.breakpoints = HashSet::from([2]);
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 2);
+
+        session.out_stream.clear();
+        session.handle_request(&request("stackTrace", json!({ "threadId": THREAD_ID })));
+        let messages = collect_messages(&session);
+        let frames = messages[0]["body"]["stackFrames"].as_array().unwrap();
+        let lines: Vec<i64> = frames.iter().map(|frame| frame["line"].as_i64().unwrap()).collect();
+        assert_eq!(lines, vec![1, 3]);
+    }
+
+    #[test]
+    fn it_should_stop_on_breakpoints_in_included_files() {
+        let dir = std::env::temp_dir().join(format!("ezfuck_dap_include_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.ez"), "def add {\n+\n}").unwrap();
+        let main_path = dir.join("main.ez").display().to_string();
+
+        let mut session = DebugSession::new(vec![]);
+        session.load_program(&main_path, "include \"lib.ez\"\n+ add > add", CompileOptions::new(true)).unwrap();
+        session.is_launched = true;
+
+        let lib_path = dir.join("lib.ez").display().to_string();
+        session.handle_request(&request("setBreakpoints", json!({ "source": { "path": lib_path }, "breakpoints": [{ "line": 2 }] })));
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 1);
+
+        session.out_stream.clear();
+        session.handle_request(&request("stackTrace", json!({ "threadId": THREAD_ID })));
+        let messages = collect_messages(&session);
+        let frame = &messages[0]["body"]["stackFrames"][0];
+        assert_eq!(frame["source"]["path"].as_str(), Some(lib_path.as_str()));
+        assert_eq!(frame["line"].as_i64(), Some(2));
+
+        // The second use of the macro stops too.
+        session.handle_request(&request("continue", json!({})));
+        session.run_slice();
+        assert_eq!(session.state.instruction_ptr, 3);
+    }
+
+    #[test]
+    fn it_should_terminate_when_the_program_ends() {
+        let mut session = start_session("+");
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+
+        assert!(session.is_terminated);
+    }
+
+    #[test]
+    fn it_should_leave_the_cell_unchanged_when_reading_past_the_end_of_the_input() {
+        let mut session = start_session("^7 , ,");
+        session.input = b"A".to_vec();
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+
+        assert!(session.is_terminated);
+        assert_eq!(session.state.cells, vec![b'A']);
+        let messages = collect_messages(&session);
+        let exited = messages.iter().find(|message| message["event"] == "exited").unwrap();
+        assert_eq!(exited["body"]["exitCode"].as_i64(), Some(0));
+    }
+
+    #[test]
+    fn it_should_send_characters_printed_across_instructions_whole() {
+        let mut session = start_session("^195 . ^169 . ^195 .");
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+
+        let messages = collect_messages(&session);
+        let outputs: Vec<&str> = messages.iter().filter(|message| message["event"] == "output").filter_map(|message| message["body"]["output"].as_str()).collect();
+        assert_eq!(outputs, vec!["é", "\u{FFFD}"]);
+    }
+
+    #[test]
+    fn it_should_report_runtime_errors_and_exit_with_a_failure() {
+        let mut session = start_session("+.<");
+        session.handle_request(&request("configurationDone", json!({})));
+        session.run_slice();
+        assert!(session.is_terminated);
+
+        let messages = collect_messages(&session);
+        let events: Vec<&Value> = messages.iter().filter(|message| message["type"] == "event").collect();
+        let names: Vec<&str> = events.iter().filter_map(|event| event["event"].as_str()).collect();
+        assert_eq!(names, vec!["output", "output", "exited", "terminated"]);
+        assert_eq!(events[1]["body"]["category"].as_str(), Some("stderr"));
+        assert_eq!(events[1]["body"]["output"].as_str(), Some("Cell Pointer Became Negative!\n"));
+        assert_eq!(events[2]["body"]["exitCode"].as_i64(), Some(1));
+    }
+}
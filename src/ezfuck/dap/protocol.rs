@@ -0,0 +1,57 @@
+use std::io::{BufRead, Write};
+use serde_json::Value;
+
+// Reads a single "Content-Length" framed message. Returns None once the stream has ended.
+pub fn read_message<R: BufRead>(in_stream: &mut R) -> Option<Value> {
+    let mut content_length: Option<usize> = None;
+
+    loop {
+        let mut header = String::new();
+        let n_read = in_stream.read_line(&mut header).ok()?;
+        if n_read == 0 {
+            return None;
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+        } else if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length?];
+    in_stream.read_exact(&mut body).ok()?;
+
+    return serde_json::from_slice(&body).ok();
+}
+
+pub fn write_message<W: Write>(out_stream: &mut W, message: &Value) -> () {
+    let body = message.to_string();
+    write!(out_stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    out_stream.flush().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn it_should_read_back_a_written_message() {
+        let message = json!({ "seq": 1, "type": "request", "command": "initialize" });
+
+        let mut buffer = vec![];
+        write_message(&mut buffer, &message);
+        write_message(&mut buffer, &message);
+
+        let mut in_stream = &buffer[..];
+        assert_eq!(read_message(&mut in_stream), Some(message.clone()));
+        assert_eq!(read_message(&mut in_stream), Some(message));
+        assert_eq!(read_message(&mut in_stream), None);
+    }
+}
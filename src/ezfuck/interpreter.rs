@@ -1 +1,5 @@
-pub mod interpreter;
\ No newline at end of file
+pub mod interpreter;
+pub mod history;
+pub mod debugger;
+pub mod interrupt;
+pub mod errors;
\ No newline at end of file
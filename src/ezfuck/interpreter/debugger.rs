@@ -0,0 +1,316 @@
+use std::cmp::{max, min};
+use crate::ezfuck::interpreter::errors::catch_error;
+use crate::ezfuck::interpreter::interpreter::{interpret_instruction, ExecutionState};
+use crate::ezfuck::interpreter::interrupt::{clear_interrupt, take_interrupt};
+use crate::ezfuck::parser::parser::{Instruction, compile_with_options};
+use crate::ezfuck::repl::cell_repr::{handle_view_command, produce_state_cells_repr};
+use crate::program_io::program_io::{Io, MemoryIo};
+
+fn produce_instructions_repr(instructions: &Vec<Instruction>, instruction_ptr: usize, show_n_around: usize) -> String {
+    let start_bound = instruction_ptr.checked_sub(show_n_around).unwrap_or(0);
+    let end_bound = min(instruction_ptr + show_n_around, instructions.len() - 1);
+    let relevant_instructions = &instructions[start_bound..=end_bound];
+
+    let instruction_ptr_places = (instructions.len().ilog10() + 1) as usize;
+
+    let mut repr = String::new();
+    for (i, instruction) in relevant_instructions.into_iter().enumerate() {
+        let current_instruction_ptr = start_bound + i;
+        let marker = if instruction_ptr == current_instruction_ptr { "> " } else { "  " };
+        repr.push_str(format!("{current_instruction_ptr:0instruction_ptr_places$} {marker}{:?}\n", instruction).as_str());
+    }
+
+    return repr;
+}
+
+fn produce_cell_changes_repr(old_cells: &Vec<u8>, new_cells: &Vec<u8>) -> String {
+    let mut repr = String::new();
+    for i in 0..max(old_cells.len(), new_cells.len()) {
+        let old_value = old_cells.get(i).copied().unwrap_or(0);
+        let new_value = new_cells.get(i).copied().unwrap_or(0);
+        if old_value != new_value {
+            repr.push_str(format!("Cell {i:0>3}: {old_value:0>3} -> {new_value:0>3}\n").as_str());
+        }
+    }
+
+    if repr.is_empty() {
+        repr.push_str("No cells were changed\n");
+    }
+
+    return repr;
+}
+
+// Code entered at the prompt is run against a scratch copy of the cells so that it can't consume the program's input,
+//  or move the program's pointers. The changed cells are only copied back into the program's state when committing.
+//  Committing is recorded in the history, so that it can be stepped back over like an instruction.
+fn evaluate_code(code: &str, state: &mut ExecutionState, should_commit: bool, console: &mut dyn Io) -> () {
+    let instructions = match catch_error(|| compile_with_options(code, &state.evaluation_options)) {
+        Ok(instructions) => instructions,
+        Err(message) => {
+            writeln!(console, "{message}").unwrap();
+            return;
+        }
+    };
+
+    let mut scratch_state = ExecutionState::with_history_size(0);
+    scratch_state.cells = state.cells.clone();
+    scratch_state.cell_ptr = state.cell_ptr;
+
+    let mut evaluation_io = MemoryIo::new(&state.evaluation_input);
+    while scratch_state.instruction_ptr < instructions.len() {
+        if take_interrupt() {
+            writeln!(console, "Interrupted").unwrap();
+            break;
+        }
+
+        let instruction = instructions[scratch_state.instruction_ptr];
+        if let Err(message) = catch_error(|| interpret_instruction(instruction, &mut scratch_state, &mut evaluation_io, false)) {
+            writeln!(console, "{message}; changes were discarded").unwrap();
+            return;
+        }
+        scratch_state.instruction_ptr += 1;
+    }
+
+    let output = evaluation_io.output;
+
+    state.evaluation_input.drain(..scratch_state.input_position);
+
+    if !output.is_empty() {
+        writeln!(console, "Output: {}", String::from_utf8_lossy(&output)).unwrap();
+    }
+
+    let changes_repr = produce_cell_changes_repr(&state.cells, &scratch_state.cells);
+    console.write_bytes(changes_repr.as_bytes());
+
+    if should_commit {
+        if state.cells != scratch_state.cells {
+            state.record_tape_snapshot();
+            state.cells = scratch_state.cells;
+        }
+    } else {
+        writeln!(console, "Dry run; changes were discarded").unwrap();
+    }
+}
+
+pub fn step_back_to_breakpoint(instructions: &Vec<Instruction>, state: &mut ExecutionState) -> usize {
+    let mut n_stepped = 0;
+    while state.step_back() {
+        n_stepped += 1;
+        if instructions.get(state.instruction_ptr) == Some(&Instruction::Breakpoint) {
+            break;
+        }
+    }
+
+    return n_stepped;
+}
+
+fn write_stepped_back(n_stepped: usize, state: &ExecutionState, console: &mut dyn Io) -> () {
+    writeln!(console, "Stepped back {n_stepped} instruction(s)").unwrap();
+    if state.history.len() == 0 {
+        writeln!(console, "Reached the start of the recorded history").unwrap();
+    }
+}
+
+// Returns true if the command was a debugger command, and false if it should be treated as code.
+fn handle_debugger_command(command: &str, instructions: &Vec<Instruction>, state: &mut ExecutionState, console: &mut dyn Io) -> bool {
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+    if let Some(result) = handle_view_command(name, argument, &mut state.tape_view) {
+        if let Err(message) = result {
+            writeln!(console, "{message}").unwrap();
+        }
+        return true;
+    }
+
+    match name {
+        ":back" => {
+            let n_steps = if argument.trim().is_empty() {
+                1
+            } else {
+                match argument.trim().parse::<usize>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        writeln!(console, "Invalid number of steps: {argument}").unwrap();
+                        return true;
+                    }
+                }
+            };
+
+            let mut n_stepped = 0;
+            while n_stepped < n_steps && state.step_back() {
+                n_stepped += 1;
+            }
+
+            write_stepped_back(n_stepped, state, console);
+        },
+        ":reverse-continue" => {
+            let n_stepped = step_back_to_breakpoint(instructions, state);
+            write_stepped_back(n_stepped, state, console);
+        },
+        ":dry" => {
+            evaluate_code(argument, state, false, console);
+        },
+        ":eval-input" => {
+            state.evaluation_input = argument.as_bytes().to_vec();
+        },
+        unknown if unknown.starts_with(":") => {
+            writeln!(console, "Unknown debugger command: {unknown}").unwrap();
+        },
+        _ => {
+            return false;
+        }
+    }
+
+    return true;
+}
+
+pub fn start_debugger<I: Io>(instructions: &Vec<Instruction>, state: &mut ExecutionState, io: &mut I) -> () {
+    // The program's output is written first, in case the console is somewhere else.
+    io.flush();
+    // Stepping backwards can go as far back as the first time the debugger was entered.
+    state.start_recording();
+
+    loop {
+        let console = io.console();
+        writeln!(console, "").unwrap();
+        let cells_repr = produce_state_cells_repr(state);
+        console.write_bytes(cells_repr.as_bytes());
+        console.flush();
+
+        let instructions_repr = produce_instructions_repr(instructions, state.instruction_ptr, 3);
+        console.write_bytes(instructions_repr.as_bytes());
+
+        console.write_bytes(b"EZ> ");
+        console.flush();
+
+        // Once there are no commands left to read, the program just continues.
+        let input_buffer = console.read_line().unwrap_or("!".to_string());
+        clear_interrupt();
+        let command = input_buffer.trim();
+
+        if handle_debugger_command(command, instructions, state, console) {
+            continue;
+        }
+
+        if command == "!" {
+            state.is_debugging = false;
+        } else if command.is_empty() == false {
+            evaluate_code(command, state, true, console);
+        }
+
+        break;
+    }
+
+    match instructions.get(state.instruction_ptr) {
+        Some(instruction) => {
+            state.record_snapshot();
+            interpret_instruction(*instruction, state, io, false);
+        }
+        None => {
+            // TODO: Is this even possible? When entering debugging mode on the last instruction?
+        }
+    }
+
+    writeln!(io.console(), "").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ezfuck::parser::parser::compile_to_intermediate;
+    use super::*;
+
+    fn run_with_history(instructions: &Vec<Instruction>) -> ExecutionState {
+        let mut state = ExecutionState::new();
+        state.start_recording();
+        let mut io = MemoryIo::new(b"");
+        while state.instruction_ptr < instructions.len() {
+            state.record_snapshot();
+            interpret_instruction(instructions[state.instruction_ptr], &mut state, &mut io, true);
+            state.instruction_ptr += 1;
+        }
+
+        return state;
+    }
+
+    #[test]
+    fn it_should_reverse_continue_to_the_previous_breakpoint() {
+        let instructions = compile_to_intermediate("+!+++", true);
+        let mut state = run_with_history(&instructions);
+        assert_eq!(state.get_current_cell(), 4);
+
+        let mut io = MemoryIo::new(b"");
+        assert!(handle_debugger_command(":reverse-continue", &instructions, &mut state, &mut io));
+        assert_eq!(state.instruction_ptr, 1);
+        assert_eq!(state.get_current_cell(), 1);
+    }
+
+    #[test]
+    fn it_should_not_change_the_program_state_during_a_dry_run() {
+        let mut state = ExecutionState::new();
+        state.set_current_cell(5);
+
+        let mut io = MemoryIo::new(b"");
+        assert!(handle_debugger_command(":dry +5>^3", &vec![], &mut state, &mut io));
+        assert_eq!(state.cells, vec![5]);
+
+        let output_string = String::from_utf8(io.output).unwrap();
+        assert!(output_string.contains("Cell 000: 005 -> 010"));
+        assert!(output_string.contains("Cell 001: 000 -> 003"));
+    }
+
+    #[test]
+    fn it_should_commit_cell_changes_but_restore_the_cell_pointer() {
+        let mut state = ExecutionState::new();
+
+        let mut io = MemoryIo::new(b"");
+        evaluate_code("+5>^3", &mut state, true, &mut io);
+        assert_eq!(state.cells, vec![5, 3]);
+        assert_eq!(state.cell_ptr, 0);
+    }
+
+    #[test]
+    fn it_should_read_evaluated_input_from_its_own_source() {
+        let mut state = ExecutionState::new();
+
+        let mut io = MemoryIo::new(b"");
+        handle_debugger_command(":eval-input AB", &vec![], &mut state, &mut io);
+        evaluate_code(",>,>,", &mut state, true, &mut io);
+
+        assert_eq!(state.cells, vec![b'A', b'B', 0]);
+        assert_eq!(state.input_position, 0);
+        assert!(state.evaluation_input.is_empty());
+    }
+
+    #[test]
+    fn it_should_step_back_over_committed_changes() {
+        let instructions = compile_to_intermediate("+!", true);
+        let mut state = run_with_history(&instructions);
+        state.instruction_ptr = 1;
+
+        let mut io = MemoryIo::new(b"");
+        evaluate_code("+5>^3", &mut state, true, &mut io);
+        assert_eq!(state.cells, vec![6, 3]);
+
+        assert!(handle_debugger_command(":back", &instructions, &mut state, &mut io));
+        assert_eq!(state.cells, vec![1]);
+        assert_eq!(state.instruction_ptr, 1);
+
+        assert!(handle_debugger_command(":back 2", &instructions, &mut state, &mut io));
+        assert_eq!(state.cells, vec![0]);
+    }
+
+    #[test]
+    fn it_should_compile_evaluated_code_with_the_session_options() {
+        let mut state = ExecutionState::new();
+        state.evaluation_options.allow_numeric_io = true;
+
+        let mut io = MemoryIo::new(b"");
+        evaluate_code("+5=", &mut state, true, &mut io);
+        assert!(String::from_utf8(io.output).unwrap().contains("Output: 5"));
+
+        let mut io = MemoryIo::new(b"");
+        state.evaluation_options.is_strict = true;
+        evaluate_code("+ x", &mut state, true, &mut io);
+        assert!(String::from_utf8(io.output).unwrap().contains("Unexpected character 'x'"));
+        assert_eq!(state.cells, vec![5]);
+    }
+}
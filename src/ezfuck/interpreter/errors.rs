@@ -0,0 +1,51 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::Once;
+
+static INSTALL_HOOK: Once = Once::new();
+
+thread_local! {
+    static IS_CATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn produce_panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+
+    return payload.downcast_ref::<&str>().map(|message| message.to_string()).unwrap_or("Unknown error".to_string());
+}
+
+// Compile and runtime errors are panics, which would end the whole session. This turns them back into a message for
+//  hosts that keep going after an error, like the REPL and the DAP server. The usual panic report is left out, since the
+//  host shows the message itself.
+pub fn catch_error<T>(action: impl FnOnce() -> T) -> Result<T, String> {
+    INSTALL_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !IS_CATCHING.with(|is_catching| is_catching.get()) {
+                default_hook(info);
+            }
+        }));
+    });
+
+    let was_catching = IS_CATCHING.with(|is_catching| is_catching.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(action));
+    IS_CATCHING.with(|is_catching| is_catching.set(was_catching));
+
+    return result.map_err(produce_panic_message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_turn_panics_into_messages() {
+        assert_eq!(catch_error(|| 5), Ok(5));
+        assert_eq!(catch_error(|| -> () { panic!("Cell {} is broken", 3) }), Err("Cell 3 is broken".to_string()));
+        assert_eq!(catch_error(|| -> () { panic!("Broken") }), Err("Broken".to_string()));
+    }
+}
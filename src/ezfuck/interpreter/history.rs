@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_HISTORY_SIZE: usize = 10_000;
+
+// Everything a single instruction can change. Instructions only ever write to the cell under the pointer, so the
+//  previous value of that one cell is enough to undo any of them. Likewise, they push or pop at most one return position.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Snapshot {
+    pub instruction_ptr: usize,
+    pub cell_ptr: usize,
+    pub cell_value: u8,
+    pub cells_len: usize,
+    pub input_position: usize,
+    pub output_position: usize,
+    pub call_stack_len: usize,
+    pub return_position: Option<usize>,
+    // The whole tape, for changes that aren't made by an instruction and can write to any cell, like committing code
+    //  evaluated in the debugger.
+    pub cells: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct History {
+    snapshots: VecDeque<Snapshot>,
+    max_size: usize,
+}
+
+impl History {
+    pub fn new(max_size: usize) -> History {
+        return History {
+            snapshots: VecDeque::new(),
+            max_size,
+        };
+    }
+
+    pub fn is_enabled(self: &Self) -> bool {
+        return self.max_size > 0;
+    }
+
+    pub fn len(self: &Self) -> usize {
+        return self.snapshots.len();
+    }
+
+    pub fn push(self: &mut Self, snapshot: Snapshot) -> () {
+        if !self.is_enabled() {
+            return;
+        }
+
+        if self.snapshots.len() >= self.max_size {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back(snapshot);
+    }
+
+    pub fn pop(self: &mut Self) -> Option<Snapshot> {
+        return self.snapshots.pop_back();
+    }
+
+    pub fn oldest(self: &Self) -> Option<&Snapshot> {
+        return self.snapshots.front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot_at(instruction_ptr: usize) -> Snapshot {
+        return Snapshot {
+            instruction_ptr,
+            cell_ptr: 0,
+            cell_value: 0,
+            cells_len: 1,
+            input_position: 0,
+            output_position: 0,
+            call_stack_len: 0,
+            return_position: None,
+            cells: None,
+        };
+    }
+
+    #[test]
+    fn it_should_drop_the_oldest_snapshots_once_full() {
+        let mut history = History::new(2);
+        history.push(snapshot_at(0));
+        history.push(snapshot_at(1));
+        history.push(snapshot_at(2));
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop(), Some(snapshot_at(2)));
+        assert_eq!(history.pop(), Some(snapshot_at(1)));
+        assert_eq!(history.pop(), None);
+    }
+
+    #[test]
+    fn it_should_not_record_anything_when_disabled() {
+        let mut history = History::new(0);
+        history.push(snapshot_at(0));
+
+        assert_eq!(history.len(), 0);
+    }
+}
@@ -1,8 +1,11 @@
-use std::cmp::min;
-use std::io;
-use std::io::{BufRead, Read, Write};
-use crate::ezfuck::parser::parser::{Instruction, EqualityOperator, MathOperator, InstructionValue, Direction, compile_to_intermediate};
-use crate::ezfuck::repl::cell_repr::{produce_cells_repr};
+use std::collections::{HashSet, VecDeque};
+use std::io::BufRead;
+use crate::ezfuck::parser::parser::{CompileOptions, Instruction, EqualityOperator, MathOperator, InstructionValue, Direction};
+use crate::ezfuck::interpreter::history::{History, Snapshot, DEFAULT_HISTORY_SIZE};
+use crate::ezfuck::interpreter::debugger::start_debugger;
+use crate::ezfuck::interpreter::interrupt::take_interrupt;
+use crate::ezfuck::repl::cell_repr::TapeView;
+use crate::program_io::program_io::{open_terminal, FlushPolicy, Io, OutputEncoding, SeparateConsoleIo, StdIo};
 
 #[derive(Clone, Debug)]
 pub struct ExecutionState {
@@ -10,15 +13,50 @@ pub struct ExecutionState {
     pub cell_ptr: usize,
     pub instruction_ptr: usize,
     pub is_debugging: bool,
+    pub history: History,
+    // Whether executed instructions are recorded in the history. Recording starts once the debugger is first entered, so
+    //  that programs that are never paused don't pay for it.
+    pub is_recording: bool,
+    // The input read since recording started, for replaying after stepping backwards. input_history_start is the input
+    //  position of its first byte, since input older than every snapshot is dropped.
+    pub input_history: VecDeque<u8>,
+    pub input_history_start: usize,
+    pub input_position: usize,
+    pub output_position: usize,
+    pub evaluation_input: Vec<u8>,
+    // What code entered at the debugger's prompt is compiled with.
+    pub evaluation_options: CompileOptions,
+    pub tape_view: TapeView,
+    // Cells that have been printed or read into since the cells were last shown.
+    pub io_cells: HashSet<usize>,
+    // The positions of the calls that haven't returned yet.
+    pub call_stack: Vec<usize>,
+    pub output_encoding: OutputEncoding,
 }
 
 impl ExecutionState {
     pub fn new() -> ExecutionState {
+        return ExecutionState::with_history_size(DEFAULT_HISTORY_SIZE);
+    }
+
+    pub fn with_history_size(history_size: usize) -> ExecutionState {
         return ExecutionState {
             cell_ptr: 0,
             instruction_ptr: 0,
             cells: vec![0],
             is_debugging: false,
+            history: History::new(history_size),
+            is_recording: false,
+            input_history: VecDeque::new(),
+            input_history_start: 0,
+            input_position: 0,
+            output_position: 0,
+            evaluation_input: vec![],
+            evaluation_options: CompileOptions::new(false),
+            tape_view: TapeView::new(),
+            io_cells: HashSet::new(),
+            call_stack: vec![],
+            output_encoding: OutputEncoding::Raw,
         };
     }
 
@@ -47,14 +85,103 @@ impl ExecutionState {
             }
         }
     }
+
+    pub fn start_recording(self: &mut Self) -> () {
+        if !self.is_recording {
+            self.is_recording = true;
+            self.drop_input_before(self.input_position);
+        }
+    }
+
+    // Where the recorded input ends. Reads before this position are replayed instead of reading new input.
+    pub fn recorded_input_end(self: &Self) -> usize {
+        return self.input_history_start + self.input_history.len();
+    }
+
+    pub fn record_snapshot(self: &mut Self) -> () {
+        let snapshot = self.produce_snapshot(None);
+        self.history.push(snapshot);
+        self.trim_input_history();
+    }
+
+    // For changes to any number of cells at once, which the single cell in a normal snapshot can't undo.
+    pub fn record_tape_snapshot(self: &mut Self) -> () {
+        let snapshot = self.produce_snapshot(Some(self.cells.clone()));
+        self.history.push(snapshot);
+        self.trim_input_history();
+    }
+
+    // Input read before the oldest snapshot can't be stepped back to, so it never needs to be replayed.
+    fn trim_input_history(self: &mut Self) -> () {
+        let keep_from = self.history.oldest().map_or(self.input_position, |snapshot| snapshot.input_position);
+        self.drop_input_before(keep_from);
+    }
+
+    fn drop_input_before(self: &mut Self, keep_from: usize) -> () {
+        while self.input_history_start < keep_from && self.input_history.pop_front().is_some() {
+            self.input_history_start += 1;
+        }
+
+        if self.input_history.is_empty() {
+            self.input_history_start = keep_from;
+        }
+    }
+
+    fn produce_snapshot(self: &Self, cells: Option<Vec<u8>>) -> Snapshot {
+        return Snapshot {
+            instruction_ptr: self.instruction_ptr,
+            cell_ptr: self.cell_ptr,
+            cell_value: self.get_current_cell(),
+            cells_len: self.cells.len(),
+            input_position: self.input_position,
+            output_position: self.output_position,
+            call_stack_len: self.call_stack.len(),
+            return_position: self.call_stack.last().copied(),
+            cells,
+        };
+    }
+
+    // Returns false if there is no more recorded history to step back through.
+    pub fn step_back(self: &mut Self) -> bool {
+        return match self.history.pop() {
+            Some(snapshot) => {
+                self.cell_ptr = snapshot.cell_ptr;
+                match snapshot.cells {
+                    Some(cells) => self.cells = cells,
+                    None => {
+                        self.cells.truncate(snapshot.cells_len);
+                        self.set_current_cell(snapshot.cell_value);
+                    },
+                }
+                self.instruction_ptr = snapshot.instruction_ptr;
+                self.input_position = snapshot.input_position;
+                self.output_position = snapshot.output_position;
+                // Undoes a call by dropping what it pushed, or a return by putting back what it popped.
+                self.call_stack.truncate(snapshot.call_stack_len);
+                if self.call_stack.len() < snapshot.call_stack_len {
+                    self.call_stack.extend(snapshot.return_position);
+                }
+                true
+            },
+            None => false,
+        }
+    }
 }
 
-fn apply_math_operator(current_cell_value: u8, operator: MathOperator, value: u8) -> u8 {
+// Returns None when dividing by zero.
+fn apply_math_operator(current_cell_value: u8, operator: MathOperator, value: u8) -> Option<u8> {
     return match operator {
-        MathOperator::Addition => current_cell_value.wrapping_add(value),
-        MathOperator::Subtraction => current_cell_value.wrapping_sub(value),
-        MathOperator::Multiplication => current_cell_value.wrapping_mul(value),
-        MathOperator::Division => current_cell_value.wrapping_div(value),
+        MathOperator::Addition => Some(current_cell_value.wrapping_add(value)),
+        MathOperator::Subtraction => Some(current_cell_value.wrapping_sub(value)),
+        MathOperator::Multiplication => Some(current_cell_value.wrapping_mul(value)),
+        MathOperator::Division => current_cell_value.checked_div(value),
+        MathOperator::Modulo => current_cell_value.checked_rem(value),
+        MathOperator::And => Some(current_cell_value & value),
+        MathOperator::Or => Some(current_cell_value | value),
+        MathOperator::Xor => Some(current_cell_value ^ value),
+        // Shifting every bit out of the cell leaves it at 0, instead of wrapping the shift amount around.
+        MathOperator::ShiftLeft => Some(current_cell_value.checked_shl(value as u32).unwrap_or(0)),
+        MathOperator::ShiftRight => Some(current_cell_value.checked_shr(value as u32).unwrap_or(0)),
     }
 }
 
@@ -65,27 +192,95 @@ fn add_cell_ptr_value(current_cell_ptr: usize, ptr_offset: isize) -> usize {
     };
 }
 
-fn print_value<W: Write>(out_stream: &mut W, cell: u8) {
-    write!(out_stream, "{}", char::from(cell)).unwrap();
-    io::stdout().flush().unwrap();
+// Returns how many bytes were written.
+fn print_value<I: Io>(io: &mut I, cell: u8, encoding: OutputEncoding) -> usize {
+    return match encoding {
+        // Every cell value is a Latin-1 character, which is encoded as the single byte of its code.
+        OutputEncoding::Raw | OutputEncoding::Latin1 => {
+            io.write_byte(cell);
+            1
+        },
+        OutputEncoding::Utf8Codepoints => {
+            let mut buffer = [0; 4];
+            let encoded = char::from(cell).encode_utf8(&mut buffer);
+            io.write_bytes(encoded.as_bytes());
+            encoded.len()
+        },
+    }
+}
+
+// Input that was read before stepping backwards is replayed instead of being read again. Returns None at the end of the
+//  input.
+fn read_input_byte<I: Io>(state: &mut ExecutionState, io: &mut I) -> Option<u8> {
+    let replayed = state.input_position.checked_sub(state.input_history_start).and_then(|i| state.input_history.get(i));
+    let input = match replayed {
+        Some(replayed) => *replayed,
+        None => {
+            let read = io.read_byte()?;
+            if state.is_recording && state.history.is_enabled() {
+                state.input_history.push_back(read);
+            }
+            read
+        },
+    };
+
+    state.input_position += 1;
+    return Some(input);
+}
+
+// Puts the byte that was just read back, so that the next read reads it again. It's kept in the input history, which
+//  already holds it if input is being recorded.
+fn unread_input_byte(state: &mut ExecutionState, byte: u8) -> () {
+    state.input_position -= 1;
+    if state.input_position < state.recorded_input_end() {
+        return;
+    }
+
+    state.input_history.clear();
+    state.input_history_start = state.input_position;
+    state.input_history.push_back(byte);
 }
 
-fn read_value<R: BufRead>(in_stream: &mut R) -> u8 {
-    let mut input = [0; 1];
-    in_stream.read_exact(&mut input).expect("Reading byte from stdin");
-    return input[0];
+// Skips whitespace before the number, and stops at the character after it, which is left to be read next. Numbers too
+//  large for a cell wrap around, like adding to a cell does. Returns None if the input ends or doesn't start with a number.
+fn read_number<I: Io>(state: &mut ExecutionState, io: &mut I) -> Option<u8> {
+    let mut next = read_input_byte(state, io);
+    while next.is_some_and(|byte| byte.is_ascii_whitespace()) {
+        next = read_input_byte(state, io);
+    }
+
+    let mut number: u8 = 0;
+    let mut n_digits = 0;
+    while let Some(digit @ b'0'..=b'9') = next {
+        number = number.wrapping_mul(10).wrapping_add(digit - b'0');
+        n_digits += 1;
+        next = read_input_byte(state, io);
+    }
+
+    if let Some(byte) = next {
+        unread_input_byte(state, byte);
+    }
+
+    if n_digits == 0 {
+        return None;
+    }
+
+    return Some(number);
 }
 
-pub fn interpret_instruction<R: BufRead, W: Write>(instruction: Instruction, state: &mut ExecutionState, in_stream: &mut R, out_stream: &mut W, allow_debugging: bool) -> () {
+pub fn interpret_instruction<I: Io>(instruction: Instruction, state: &mut ExecutionState, io: &mut I, allow_debugging: bool) -> () {
     match instruction {
         Instruction::ApplyOperatorToCell { operator, value } => {
-            let actual_value = value.determine_value(state.get_current_cell());
-            let new_cell_value = apply_math_operator(state.get_current_cell(), operator, actual_value);
-            state.set_current_cell(new_cell_value);
+            // Numbers given to cell instructions are checked to fit in a cell when parsing.
+            let actual_value = value.determine_value(&state.cells, state.cell_ptr) as u8;
+            match apply_math_operator(state.get_current_cell(), operator, actual_value) {
+                Some(new_cell_value) => state.set_current_cell(new_cell_value),
+                None => panic!("Divided by zero at instruction {}", state.instruction_ptr),
+            }
         }
 
         Instruction::AddToCellPtr { direction, offset } => {
-            let abs_offset = offset.determine_value(state.get_current_cell());
+            let abs_offset = offset.determine_value(&state.cells, state.cell_ptr);
             let signed_offset = if direction == Direction::Left { abs_offset as isize * -1 } else { abs_offset as isize };
             let new_cell_ptr = add_cell_ptr_value(state.cell_ptr, signed_offset);
             state.set_cell_pointer(new_cell_ptr);
@@ -107,135 +302,146 @@ pub fn interpret_instruction<R: BufRead, W: Write>(instruction: Instruction, sta
         }
 
         Instruction::PrintOut => {
-            print_value(out_stream, state.get_current_cell());
+            state.output_position += print_value(io, state.get_current_cell(), state.output_encoding);
+            state.io_cells.insert(state.cell_ptr);
+        }
+
+        // Strings are already encoded when they're compiled, so their bytes are written as they are.
+        Instruction::PrintConst { byte } => {
+            io.write_byte(byte);
+            state.output_position += 1;
         }
 
+        // Reading past the end of the input leaves the cell unchanged.
         Instruction::ReadIn => {
-            let input = read_value(in_stream);
-            state.set_current_cell(input);
+            if let Some(input) = read_input_byte(state, io) {
+                state.io_cells.insert(state.cell_ptr);
+                state.set_current_cell(input);
+            }
+        }
+
+        Instruction::PrintNumber => {
+            let digits = state.get_current_cell().to_string();
+            write!(io, "{digits}").unwrap();
+            state.output_position += digits.len();
+            state.io_cells.insert(state.cell_ptr);
+        }
+
+        Instruction::ReadNumber => {
+            if let Some(input) = read_number(state, io) {
+                state.io_cells.insert(state.cell_ptr);
+                state.set_current_cell(input);
+            }
         }
 
         Instruction::SetCell { value } => {
-            let actual_value = value.determine_value(state.get_current_cell());
+            let actual_value = value.determine_value(&state.cells, state.cell_ptr) as u8;
             state.set_current_cell(actual_value);
         }
+        Instruction::SetCellPtr { position } => {
+            state.set_cell_pointer(position);
+        }
         Instruction::Breakpoint => {
             if allow_debugging {
                 state.is_debugging = true;
             }
         }
+        Instruction::Label => (),
+        Instruction::Jump { position } => {
+            state.set_instruction_pointer(position);
+        }
+        Instruction::Call { position } => {
+            state.call_stack.push(state.instruction_ptr);
+            state.set_instruction_pointer(position);
+        }
+        Instruction::Return => {
+            // Returning to the call means the instruction after it runs next, since the pointer is advanced afterwards.
+            match state.call_stack.pop() {
+                Some(call_position) => state.set_instruction_pointer(call_position),
+                None => panic!("Returned at instruction {} without a matching call", state.instruction_ptr),
+            }
+        }
+    }
+}
+
+// Returns false if execution should stop.
+fn handle_interrupt<I: Io>(state: &mut ExecutionState, io: &mut I, allow_debugging: bool) -> bool {
+    return if allow_debugging {
+        writeln!(io.console(), "\nInterrupted; entering the debugger").unwrap();
+        state.is_debugging = true;
+        true
+    } else {
+        writeln!(io.console(), "\nInterrupted").unwrap();
+        false
     }
 }
 
-pub fn interpret<R: BufRead, W: Write>(instructions: &Vec<Instruction>, state: &mut ExecutionState, in_stream: &mut R, out_stream: &mut W, allow_debugging: bool) -> () {
+pub fn interpret<I: Io>(instructions: &Vec<Instruction>, state: &mut ExecutionState, io: &mut I, allow_debugging: bool) -> () {
     while state.instruction_ptr < instructions.len() {
+        if take_interrupt() && !handle_interrupt(state, io, allow_debugging) {
+            io.flush();
+            return;
+        }
+
         if state.is_debugging {
-            start_debugger(&instructions, state, in_stream, out_stream);
+            start_debugger(&instructions, state, io);
         } else {
             let current_instruction = instructions[state.instruction_ptr];
-            interpret_instruction(current_instruction, state, in_stream, out_stream, allow_debugging);
+            if allow_debugging && state.is_recording {
+                state.record_snapshot();
+            }
+            interpret_instruction(current_instruction, state, io, allow_debugging);
         }
 
         state.instruction_ptr += 1;
     }
-}
-
-fn produce_instructions_repr(instructions: &Vec<Instruction>, instruction_ptr: usize, show_n_around: usize) -> String {
-    let start_bound = instruction_ptr.checked_sub(show_n_around).unwrap_or(0);
-    let end_bound = min(instruction_ptr + show_n_around, instructions.len() - 1);
-    let relevant_instructions = &instructions[start_bound..=end_bound];
-
-    let instruction_ptr_places = (instructions.len().ilog10() + 1) as usize;
 
-    let mut repr = String::new();
-    for (i, instruction) in relevant_instructions.into_iter().enumerate() {
-        let current_instruction_ptr = start_bound + i;
-        let marker = if instruction_ptr == current_instruction_ptr { "> " } else { "  " };
-        repr.push_str(format!("{current_instruction_ptr:0instruction_ptr_places$} {marker}{:?}\n", instruction).as_str());
-    }
-
-    return repr;
+    // Output that was buffered by the flush policy is written once the program finishes.
+    io.flush();
 }
 
-fn start_debugger<R: BufRead, W: Write>(instructions: &Vec<Instruction>, state: &mut ExecutionState, in_stream: &mut R, out_stream: &mut W) -> () {
-    writeln!(out_stream, "").unwrap();
-    let cells_repr = produce_cells_repr(&state.cells, state.cell_ptr);
-    out_stream.write(cells_repr.as_bytes()).unwrap();
-    out_stream.flush().unwrap();
-
-    let instructions_repr = produce_instructions_repr(instructions, state.instruction_ptr, 3);
-    out_stream.write(instructions_repr.as_bytes()).unwrap();
-
-    out_stream.write(b"EZ> ").unwrap();
-    out_stream.flush().unwrap();
-
-    let mut input_buffer: String = String::new();
-    in_stream.read_line(&mut input_buffer).unwrap();
-
-    if input_buffer.starts_with("!") {
-        state.is_debugging = false;
-    } else if input_buffer.is_empty() == false {
-        let dbg_instructions = compile_to_intermediate(&input_buffer, false);
-
-        let current_instruction_ptr = state.instruction_ptr;
-        state.instruction_ptr = 0;
-
-        let current_cell_ptr = state.cell_ptr;
-
-        interpret(&dbg_instructions, state, in_stream, out_stream, false);
-
-        state.cell_ptr = current_cell_ptr;
-        state.instruction_ptr = current_instruction_ptr;
-
-        out_stream.write(b"\n").unwrap();
-    }
+// The program writes to stdout, and reads from program_input, or stdin if there isn't any. The debugger uses the
+//  terminal if there is one, so that it doesn't compete with the program for its input. Without a terminal it falls
+//  back to stdin, unless the program is already reading from it.
+pub fn interpret_with_std_io(instructions: &Vec<Instruction>, state: &mut ExecutionState, program_input: Option<Box<dyn BufRead>>, flush_policy: FlushPolicy, allow_debugging: bool) -> () {
+    let is_reading_stdin = program_input.is_none();
+    let mut program_io = match program_input {
+        Some(reader) => StdIo::with_input(reader, flush_policy),
+        None => StdIo::stdio(flush_policy),
+    };
 
-    match instructions.get(state.instruction_ptr) {
-        Some(instruction) => {
-            interpret_instruction(*instruction, state, in_stream, out_stream, false);
-        }
+    match open_terminal() {
+        Some(terminal) => interpret(instructions, state, &mut SeparateConsoleIo::new(program_io, terminal), allow_debugging),
+        None if is_reading_stdin => interpret(instructions, state, &mut program_io, allow_debugging),
         None => {
-            // TODO: Is this even possible? When entering debugging mode on the last instruction?
-        }
+            let console = StdIo::stdio(FlushPolicy::Byte);
+            interpret(instructions, state, &mut SeparateConsoleIo::new(program_io, console), allow_debugging);
+        },
     }
-
-    writeln!(out_stream, "").unwrap();
-}
-
-pub fn interpret_with_std_io(instructions: &Vec<Instruction>, allow_debugging: bool) -> () {
-    let stdin = io::stdin();
-    let mut input = stdin.lock();
-
-    let mut stdout = io::stdout();
-
-    let mut state = ExecutionState::new();
-
-    interpret(instructions, &mut state, &mut input, &mut stdout, allow_debugging);
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::ezfuck::parser::parser::compile_to_intermediate;
+    use crate::ezfuck::parser::parser::{compile_to_intermediate, compile_with_options, CompileOptions};
+    use crate::program_io::program_io::MemoryIo;
     use super::*;
 
     fn interpret_and_collect_output(instructions: &Vec<Instruction>, state: &mut ExecutionState, input: &[u8]) -> String {
-        let mut input = &input[..];
-        let mut output = vec![];
+        let mut io = MemoryIo::new(input);
 
-        interpret(&instructions, state, &mut input, &mut output, false);
+        interpret(&instructions, state, &mut io, false);
 
-        let output_string = String::from_utf8(output).unwrap();
+        let output_string = String::from_utf8(io.output).unwrap();
         return output_string;
     }
 
     fn interpret_instruction_and_collect_output(instruction: Instruction, state: &mut ExecutionState, input: &[u8]) -> String {
-        let mut input = &input[..];
-        let mut output = vec![];
+        let mut io = MemoryIo::new(input);
 
 
-        interpret_instruction(instruction, state, &mut input, &mut output, false);
+        interpret_instruction(instruction, state, &mut io, false);
 
-        let output_string = String::from_utf8(output).unwrap();
+        let output_string = String::from_utf8(io.output).unwrap();
         return output_string;
     }
 
@@ -290,6 +496,27 @@ mod tests {
         assert_eq!(state.cells, vec![10]);
     }
 
+    #[test]
+    fn it_should_apply_modulo_and_bitwise_operators() {
+        let cases = [
+            (MathOperator::Modulo, 3, 1),
+            (MathOperator::And, 0b1100, 0b1000),
+            (MathOperator::Or, 0b0011, 0b1011),
+            (MathOperator::Xor, 0b1111, 0b0101),
+            (MathOperator::ShiftLeft, 2, 0b101000),
+            (MathOperator::ShiftRight, 2, 0b10),
+            (MathOperator::ShiftLeft, 8, 0),
+        ];
+
+        for (operator, value, expected) in cases {
+            let mut state = ExecutionState::new();
+            state.set_current_cell(0b1010);
+            let instruction = Instruction::ApplyOperatorToCell { operator, value: InstructionValue::Number(value) };
+            interpret_instruction_and_collect_output(instruction, &mut state, b"");
+            assert_eq!(state.get_current_cell(), expected, "{operator:?} {value}");
+        }
+    }
+
     #[test]
     fn it_should_jump_the_instruction_pointer_if_equal() {
         let instruction = Instruction::JumpToIf {
@@ -457,4 +684,285 @@ mod tests {
         interpret_instruction_and_collect_output(decrement, &mut state, b"");
         assert_eq!(state.get_current_cell(), 254);
     }
+
+    #[test]
+    fn it_should_move_to_and_read_from_named_cells() {
+        let instructions = compile_to_intermediate("+5 @C +@A *@C", false);
+
+        let mut state = ExecutionState::new();
+        interpret_and_collect_output(&instructions, &mut state, b"");
+        assert_eq!(state.cells, vec![5, 0, 25]);
+        assert_eq!(state.cell_ptr, 2);
+    }
+
+    #[test]
+    fn it_should_use_the_values_of_cells_relative_to_the_current_cell() {
+        let instructions = compile_to_intermediate("^3 >^4 >^5 < *@>1 -@<1", false);
+
+        let mut state = ExecutionState::new();
+        interpret_and_collect_output(&instructions, &mut state, b"");
+        assert_eq!(state.cells, vec![3, 17, 5]);
+    }
+
+    #[test]
+    fn it_should_loop_until_the_cell_matches_the_loop_value() {
+        let instructions = compile_to_intermediate("^3 [10 + >+< ]", false);
+
+        let mut state = ExecutionState::new();
+        interpret_and_collect_output(&instructions, &mut state, b"");
+        assert_eq!(state.cells, vec![10, 7]);
+    }
+
+    #[test]
+    fn it_should_run_if_blocks_at_most_once() {
+        let instructions = compile_to_intermediate("^3 ?[ +2 >^9< ] >> ?[ ^1 ] > ^5 ?[5 ^1 ]", false);
+
+        let mut state = ExecutionState::new();
+        interpret_and_collect_output(&instructions, &mut state, b"");
+        assert_eq!(state.cells, vec![5, 9, 0, 5]);
+    }
+
+    #[test]
+    fn it_should_call_subroutines_and_jump_to_labels() {
+        // Skips over the subroutine, then calls it twice. Each call adds 3 to the current cell and moves right.
+        let instructions = compile_to_intermediate("{>main} {add_three} +3 > {} {main} {+add_three} {+add_three} ^7", false);
+
+        let mut state = ExecutionState::new();
+        interpret_and_collect_output(&instructions, &mut state, b"");
+        assert_eq!(state.cells, vec![3, 3, 7]);
+        assert!(state.call_stack.is_empty());
+    }
+
+    #[test]
+    fn it_should_restore_the_call_stack_when_stepping_back() {
+        let instructions = compile_to_intermediate("{>main} {sub} + {} {main} {+sub}", false);
+
+        let mut state = ExecutionState::new();
+        state.start_recording();
+        interpret(&instructions, &mut state, &mut MemoryIo::new(b""), true);
+        assert!(state.call_stack.is_empty());
+
+        // Undoes the return.
+        assert!(state.step_back());
+        assert_eq!(state.call_stack, vec![5]);
+
+        // Undoes the increment, then the call.
+        assert!(state.step_back());
+        assert!(state.step_back());
+        assert!(state.call_stack.is_empty());
+        assert_eq!(state.instruction_ptr, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "Divided by zero at instruction 4")]
+    fn it_should_panic_when_dividing_by_a_cell_holding_zero() {
+        let instructions = compile_to_intermediate("^5 > ^0 < %@>1", false);
+        interpret_and_collect_output(&instructions, &mut ExecutionState::new(), b"");
+    }
+
+    #[test]
+    #[should_panic(expected = "without a matching call")]
+    fn it_should_panic_when_returning_without_a_call() {
+        let instructions = compile_to_intermediate("+ {}", false);
+        interpret_and_collect_output(&instructions, &mut ExecutionState::new(), b"");
+    }
+
+    #[test]
+    fn it_should_restore_the_previous_state_when_stepping_back() {
+        let instructions = compile_to_intermediate("+5>2+3", false);
+
+        let mut state = ExecutionState::new();
+        state.start_recording();
+        interpret(&instructions, &mut state, &mut MemoryIo::new(b""), true);
+        assert_eq!(state.cells, vec![5, 0, 3]);
+
+        assert!(state.step_back());
+        assert_eq!(state.cells, vec![5, 0, 0]);
+        assert_eq!(state.instruction_ptr, 2);
+
+        assert!(state.step_back());
+        assert_eq!(state.cells, vec![5]);
+        assert_eq!(state.cell_ptr, 0);
+
+        assert!(state.step_back());
+        assert_eq!(state.cells, vec![0]);
+        assert_eq!(state.instruction_ptr, 0);
+
+        assert!(!state.step_back());
+    }
+
+    #[test]
+    fn it_should_replay_input_after_stepping_back_over_a_read() {
+        let instructions = compile_to_intermediate(",", false);
+
+        let mut state = ExecutionState::new();
+        state.start_recording();
+        let mut io = MemoryIo::new(b"AB");
+        interpret(&instructions, &mut state, &mut io, true);
+        assert!(state.step_back());
+        assert_eq!(state.get_current_cell(), 0);
+
+        interpret(&instructions, &mut state, &mut io, true);
+        assert_eq!(state.get_current_cell(), b'A');
+        assert_eq!(state.input_position, 1);
+    }
+
+    #[test]
+    fn it_should_only_record_history_once_the_debugger_is_entered() {
+        let instructions = compile_to_intermediate(",,", false);
+
+        let mut state = ExecutionState::new();
+        interpret(&instructions, &mut state, &mut MemoryIo::new(b"AB"), true);
+        assert!(!state.step_back());
+        assert!(state.input_history.is_empty());
+    }
+
+    #[test]
+    fn it_should_drop_input_older_than_the_oldest_snapshot() {
+        let instructions = compile_to_intermediate(",,,", false);
+
+        let mut state = ExecutionState::with_history_size(2);
+        state.start_recording();
+        interpret(&instructions, &mut state, &mut MemoryIo::new(b"ABC"), true);
+        assert_eq!(state.input_history, vec![b'B', b'C']);
+        assert_eq!(state.input_history_start, 1);
+
+        // Stepping back over the last read replays it, even though the first byte was dropped.
+        assert!(state.step_back());
+        interpret(&instructions, &mut state, &mut MemoryIo::new(b""), true);
+        assert_eq!(state.get_current_cell(), b'C');
+    }
+
+    #[test]
+    fn it_should_not_record_history_when_debugging_is_disallowed() {
+        let instructions = compile_to_intermediate("+++", false);
+
+        let mut state = ExecutionState::new();
+        interpret_and_collect_output(&instructions, &mut state, b"");
+
+        assert!(!state.step_back());
+    }
+
+    #[test]
+    fn it_should_enter_the_debugger_when_interrupted_if_debugging_is_allowed() {
+        let mut state = ExecutionState::new();
+        assert!(handle_interrupt(&mut state, &mut MemoryIo::new(b""), true));
+        assert!(state.is_debugging);
+    }
+
+    #[test]
+    fn it_should_stop_when_interrupted_if_debugging_is_disallowed() {
+        let mut state = ExecutionState::new();
+        assert!(!handle_interrupt(&mut state, &mut MemoryIo::new(b""), false));
+        assert!(!state.is_debugging);
+    }
+
+    fn compile_with_numeric_io(code: &str) -> Vec<Instruction> {
+        let mut options = CompileOptions::new(false);
+        options.allow_numeric_io = true;
+        return compile_with_options(code, &options);
+    }
+
+    #[test]
+    fn it_should_read_and_print_decimal_numbers() {
+        let instructions = compile_with_numeric_io(";>;<+@>1= \",\" ; =");
+        let mut state = ExecutionState::new();
+        let output = interpret_and_collect_output(&instructions, &mut state, b"  12\n30 300");
+
+        assert_eq!(output, "42,44");
+        assert_eq!(state.input_position, 11);
+    }
+
+    #[test]
+    fn it_should_leave_the_cell_unchanged_when_the_input_is_not_a_number_or_has_ended() {
+        let instructions = compile_with_numeric_io("^7 ; = > ^8 ; = > , . > ^9 ; =");
+        let mut state = ExecutionState::new();
+        let output = interpret_and_collect_output(&instructions, &mut state, b" x");
+
+        assert_eq!(output, "78x9");
+        assert_eq!(state.input_position, 2);
+    }
+
+    #[test]
+    fn it_should_leave_the_character_after_a_number_to_be_read_next() {
+        let instructions = compile_with_numeric_io("; > , > ; > ,");
+        let mut state = ExecutionState::new();
+        interpret_and_collect_output(&instructions, &mut state, b"12a34b");
+        assert_eq!(state.cells, vec![12, b'a', 34, b'b']);
+
+        let mut state = ExecutionState::with_history_size(10);
+        state.start_recording();
+        interpret_and_collect_output(&instructions, &mut state, b"12a34b");
+        assert_eq!(state.cells, vec![12, b'a', 34, b'b']);
+    }
+
+    #[test]
+    fn it_should_print_raw_bytes_unless_given_an_encoding() {
+        let instructions = compile_to_intermediate("^233 . \"é\"", false);
+
+        let mut io = MemoryIo::new(b"");
+        let mut state = ExecutionState::new();
+        interpret(&instructions, &mut state, &mut io, false);
+        assert_eq!(io.output, vec![233, 0xc3, 0xa9]);
+        assert_eq!(state.output_position, 3);
+
+        let mut io = MemoryIo::new(b"");
+        let mut state = ExecutionState::new();
+        state.output_encoding = OutputEncoding::Utf8Codepoints;
+        interpret(&instructions, &mut state, &mut io, false);
+        assert_eq!(String::from_utf8(io.output).unwrap(), "éé");
+        assert_eq!(state.output_position, 4);
+    }
+
+    #[test]
+    fn it_should_write_latin1_as_single_bytes_and_codepoints_as_utf8() {
+        let instructions = compile_to_intermediate("^200 .", false);
+        for (encoding, expected) in [(OutputEncoding::Latin1, vec![200]), (OutputEncoding::Utf8Codepoints, vec![0xc3, 0x88])] {
+            let mut io = MemoryIo::new(b"");
+            let mut state = ExecutionState::new();
+            state.output_encoding = encoding;
+            interpret(&instructions, &mut state, &mut io, false);
+            assert_eq!(io.output, expected);
+        }
+    }
+
+    #[test]
+    fn it_should_ignore_numeric_io_unless_enabled() {
+        assert_eq!(compile_to_intermediate("+=;", false).len(), 1);
+        assert_eq!(compile_to_intermediate("+ =5", false), vec![Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(5) }]);
+        assert_eq!(compile_with_numeric_io(":5 =").len(), 1);
+    }
+
+    #[test]
+    fn it_should_leave_the_cell_unchanged_when_reading_past_the_end_of_the_input() {
+        let instructions = compile_to_intermediate("^7 , . , .", false);
+        let output = interpret_and_collect_output(&instructions, &mut ExecutionState::new(), b"A");
+
+        assert_eq!(output, "AA");
+    }
+
+    #[test]
+    fn it_should_read_debugger_commands_from_the_console() {
+        let instructions = compile_to_intermediate(",!,.", true);
+
+        let mut io = SeparateConsoleIo::new(MemoryIo::new(b"AB"), MemoryIo::new(b"!\n"));
+        let mut state = ExecutionState::new();
+        interpret(&instructions, &mut state, &mut io, true);
+
+        assert_eq!(io.program.output, b"B");
+        assert_eq!(state.input_position, 2);
+        assert!(String::from_utf8(io.console.output).unwrap().contains("EZ> "));
+    }
+
+    #[test]
+    fn it_should_continue_once_the_console_has_no_commands_left() {
+        let instructions = compile_to_intermediate("!+!+", true);
+
+        let mut io = SeparateConsoleIo::new(MemoryIo::new(b""), MemoryIo::new(b""));
+        let mut state = ExecutionState::new();
+        interpret(&instructions, &mut state, &mut io, true);
+
+        assert_eq!(state.cells[0], 2);
+        assert!(!state.is_debugging);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,24 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::program_io::program_io::flush_stdout_before_exit;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn install_interrupt_handler() -> () {
+    ctrlc::set_handler(|| {
+        // If the first Ctrl+C still hasn't been handled, we're blocked somewhere that can't be paused (like waiting for
+        //  input), so a second one exits instead, after writing out the output the program has buffered so far.
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            flush_stdout_before_exit();
+            std::process::exit(130);
+        }
+    }).expect("Setting Ctrl+C handler");
+}
+
+// Returns true if Ctrl+C was pressed since the last time this was called.
+pub fn take_interrupt() -> bool {
+    return INTERRUPTED.load(Ordering::Relaxed) && INTERRUPTED.swap(false, Ordering::SeqCst);
+}
+
+pub fn clear_interrupt() -> () {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
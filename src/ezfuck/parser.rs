@@ -1 +1,4 @@
 pub mod parser;
+pub mod expression;
+pub mod macros;
+pub mod include;
\ No newline at end of file
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+// Evaluates constant expressions used as arguments, like the "3*7" in "+(3*7)". Supports +, -, *, / and %, with the usual
+//  precedence, and parentheses. Since arguments can't be negative, an expression that goes below 0 is an error.
+pub fn evaluate_constant_expression(expression: &str) -> Result<usize, String> {
+    return evaluate_expression(expression, &HashMap::new());
+}
+
+// Like evaluate_constant_expression, but names like "n" can be used, and are replaced with their values. Used for the
+//  parameters of macros.
+pub fn evaluate_expression(expression: &str, variables: &HashMap<String, usize>) -> Result<usize, String> {
+    let symbols: Vec<char> = expression.chars().filter(|symbol| !symbol.is_whitespace()).collect();
+
+    let mut parser = ExpressionParser { symbols, i: 0, variables };
+    let value = parser.parse_sum()?;
+    return match parser.peek() {
+        None => Ok(value),
+        Some(symbol) => Err(format!("Unexpected {symbol:?} in expression")),
+    }
+}
+
+struct ExpressionParser<'a> {
+    symbols: Vec<char>,
+    i: usize,
+    variables: &'a HashMap<String, usize>,
+}
+
+impl ExpressionParser<'_> {
+    fn peek(self: &Self) -> Option<char> {
+        return self.symbols.get(self.i).copied();
+    }
+
+    fn parse_sum(self: &mut Self) -> Result<usize, String> {
+        let mut value = self.parse_product()?;
+        while let Some(operator @ ('+' | '-')) = self.peek() {
+            self.i += 1;
+            let operand = self.parse_product()?;
+            value = if operator == '+' {
+                value.checked_add(operand).ok_or("Expression is too large")?
+            } else {
+                value.checked_sub(operand).ok_or("Expression is negative")?
+            };
+        }
+
+        return Ok(value);
+    }
+
+    fn parse_product(self: &mut Self) -> Result<usize, String> {
+        let mut value = self.parse_operand()?;
+        while let Some(operator @ ('*' | '/' | '%')) = self.peek() {
+            self.i += 1;
+            let operand = self.parse_operand()?;
+            value = match operator {
+                '*' => value.checked_mul(operand).ok_or("Expression is too large")?,
+                '/' => value.checked_div(operand).ok_or("Division by zero in expression")?,
+                _ => value.checked_rem(operand).ok_or("Division by zero in expression")?,
+            };
+        }
+
+        return Ok(value);
+    }
+
+    fn parse_operand(self: &mut Self) -> Result<usize, String> {
+        return match self.peek() {
+            Some('(') => {
+                self.i += 1;
+                let value = self.parse_sum()?;
+                if self.peek() != Some(')') {
+                    return Err("( missing a matching ) in expression".to_string());
+                }
+                self.i += 1;
+                Ok(value)
+            },
+            Some(digit) if digit.is_ascii_digit() => {
+                let digits: String = self.symbols[self.i..].iter().take_while(|digit| digit.is_ascii_digit()).collect();
+                self.i += digits.len();
+                digits.parse::<usize>().map_err(|_| format!("{digits} is too large"))
+            },
+            Some(letter) if is_name_start(letter) => {
+                let name: String = self.symbols[self.i..].iter().take_while(|letter| is_name_part(**letter)).collect();
+                self.i += name.len();
+                self.variables.get(&name).copied().ok_or(format!("Unknown name {name} in expression"))
+            },
+            Some(symbol) => Err(format!("Unexpected {symbol:?} in expression")),
+            None => Err("Expression ended early".to_string()),
+        }
+    }
+}
+
+// Names start with a lowercase letter or an underscore, so that they can't be mistaken for cell names or "V".
+pub fn is_name_start(letter: char) -> bool {
+    return letter.is_ascii_lowercase() || letter == '_';
+}
+
+pub fn is_name_part(letter: char) -> bool {
+    return letter.is_ascii_alphanumeric() || letter == '_';
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_evaluate_with_precedence_and_parentheses() {
+        assert_eq!(evaluate_constant_expression("3*7"), Ok(21));
+        assert_eq!(evaluate_constant_expression("2 + 3 * 4"), Ok(14));
+        assert_eq!(evaluate_constant_expression("(2 + 3) * 4"), Ok(20));
+        assert_eq!(evaluate_constant_expression("100 / 7 % 4 - 2"), Ok(0));
+    }
+
+    #[test]
+    fn it_should_reject_invalid_expressions() {
+        assert!(evaluate_constant_expression("2 - 3").is_err());
+        assert!(evaluate_constant_expression("5 / (2 - 2)").is_err());
+        assert!(evaluate_constant_expression("(1 + 2").is_err());
+        assert!(evaluate_constant_expression("1 +").is_err());
+        assert!(evaluate_constant_expression("V").is_err());
+        assert!(evaluate_constant_expression("n + 1").is_err());
+    }
+
+    #[test]
+    fn it_should_replace_names_with_their_values() {
+        let variables = HashMap::from([("n".to_string(), 4), ("step_2".to_string(), 3)]);
+        assert_eq!(evaluate_expression("n * step_2 + 1", &variables), Ok(13));
+        assert_eq!(evaluate_expression("m", &variables), Err("Unknown name m in expression".to_string()));
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::ezfuck::parser::parser::{describe_position, lex, Source, Token, INCLUDE_SYMBOL};
+
+struct IncludeResolver<'a> {
+    include_paths: &'a Vec<PathBuf>,
+    sources: Vec<Source>,
+    // The canonical paths of every file that's been included, so that each file is only included once.
+    included_paths: Vec<PathBuf>,
+    // The sources currently being included, outermost first, to detect files that include each other.
+    include_stack: Vec<usize>,
+}
+
+impl IncludeResolver<'_> {
+    // Included paths are relative to the directory of the file including them, or the current directory for code that
+    //  wasn't read from a file. The include paths are searched after that.
+    fn find_included_file(self: &Self, source_i: usize, token: &Token) -> PathBuf {
+        let source = &self.sources[source_i];
+        let relative_path = token.name.as_ref().unwrap();
+
+        let including_dir = source.path.as_ref().and_then(|path| path.parent()).unwrap_or(Path::new("")).to_path_buf();
+        let mut search_dirs = vec![including_dir];
+        search_dirs.extend(self.include_paths.iter().cloned());
+
+        for dir in &search_dirs {
+            let candidate = dir.join(relative_path);
+            if candidate.is_file() {
+                return candidate;
+            }
+        }
+
+        let searched: Vec<String> = search_dirs.iter().map(|dir| format!("{:?}", dir.display().to_string())).collect();
+        panic!("Could not find included file {relative_path:?} at {}. Searched: {}", describe_position(source, token.position), searched.join(", "));
+    }
+
+    fn describe_cycle(self: &Self, included_path: &Path) -> String {
+        let mut chain: Vec<String> = self.include_stack.iter()
+            .map(|source_i| self.sources[*source_i].path.as_ref().map_or("<input>".to_string(), |path| path.display().to_string()))
+            .collect();
+        chain.push(included_path.display().to_string());
+        return chain.join(" -> ");
+    }
+
+    // Returns the tokens of the source, with the tokens of each file it includes placed after its include.
+    fn lex_with_includes(self: &mut Self, source_i: usize) -> Vec<Token> {
+        let mut tokens = vec![];
+        for token in lex(&self.sources[source_i]) {
+            let is_include = token.instruction_symbol == INCLUDE_SYMBOL;
+            tokens.push(token.clone());
+            if is_include {
+                tokens.extend(self.include(source_i, &token));
+            }
+        }
+
+        return tokens;
+    }
+
+    fn include(self: &mut Self, source_i: usize, token: &Token) -> Vec<Token> {
+        let path = self.find_included_file(source_i, token);
+        let canonical_path = fs::canonicalize(&path).unwrap_or(path.clone());
+
+        let is_being_included = self.include_stack.iter().any(|stack_i| self.canonical_path_of(*stack_i).as_ref() == Some(&canonical_path));
+        if is_being_included {
+            let position = describe_position(&self.sources[source_i], token.position);
+            panic!("Including {:?} at {position} creates a cycle: {}", token.name.as_ref().unwrap(), self.describe_cycle(&path));
+        }
+
+        // Like an import, a file that was already included elsewhere doesn't need to be included again.
+        if self.included_paths.contains(&canonical_path) {
+            return vec![];
+        }
+
+        let code = match fs::read_to_string(&path) {
+            Ok(code) => code,
+            Err(err) => panic!("Could not read included file {}: {err}", path.display()),
+        };
+
+        let included_i = self.sources.len();
+        self.sources.push(Source::new(Some(path), included_i, &code, self.sources[source_i].allow_numeric_io));
+        self.included_paths.push(canonical_path);
+
+        self.include_stack.push(included_i);
+        let tokens = self.lex_with_includes(included_i);
+        self.include_stack.pop();
+
+        return tokens;
+    }
+
+    fn canonical_path_of(self: &Self, source_i: usize) -> Option<PathBuf> {
+        let path = self.sources[source_i].path.as_ref()?;
+        return Some(fs::canonicalize(path).unwrap_or(path.clone()));
+    }
+}
+
+// Lexes the main source and every file it includes. Returns the tokens of all of them, with the includes still in place
+//  so that they can be checked in strict mode, and the sources the tokens are from.
+pub fn resolve_includes(main_source: Source, include_paths: &Vec<PathBuf>) -> (Vec<Token>, Vec<Source>) {
+    let mut resolver = IncludeResolver { include_paths, sources: vec![main_source], included_paths: vec![], include_stack: vec![0] };
+    if let Some(main_path) = resolver.canonical_path_of(0) {
+        resolver.included_paths.push(main_path);
+    }
+
+    let tokens = resolver.lex_with_includes(0);
+    return (tokens, resolver.sources);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use crate::ezfuck::parser::parser::{compile_with_options, compile_with_source_map, CompileOptions, Instruction, InstructionValue, MathOperator};
+    use super::*;
+
+    // Creates a directory for the test holding the given files.
+    fn create_files(test_name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ezfuck_include_{test_name}_{}", std::process::id()));
+        for (path, code) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, code).unwrap();
+        }
+
+        return dir;
+    }
+
+    #[test]
+    fn it_should_include_files_relative_to_the_including_file() {
+        let dir = create_files("relative", &[
+            ("main.ez", "include \"lib/print.ez\"\n^65 print"),
+            ("lib/print.ez", "include \"newline.ez\"\ndef print { . newline }"),
+            ("lib/newline.ez", "def newline { ^10 . }"),
+        ]);
+
+        let main_path = dir.join("main.ez");
+        let code = fs::read_to_string(&main_path).unwrap();
+        let (instructions, source_map) = compile_with_source_map(&code, Some(&main_path), &CompileOptions::new(false));
+
+        assert_eq!(instructions, vec![
+            Instruction::SetCell { value: InstructionValue::Number(65) },
+            Instruction::PrintOut,
+            Instruction::SetCell { value: InstructionValue::Number(10) },
+            Instruction::PrintOut,
+        ]);
+
+        let files: Vec<usize> = source_map.positions.iter().map(|position| position.file).collect();
+        assert_eq!(files, vec![0, 1, 2, 2]);
+        assert_eq!(source_map.sources[2].path, Some(dir.join("lib").join("newline.ez")));
+    }
+
+    #[test]
+    fn it_should_search_the_include_paths_and_only_include_files_once() {
+        let dir = create_files("search", &[("lib/add.ez", "+")]);
+
+        let mut options = CompileOptions::new(false);
+        options.include_paths = vec![dir.join("lib")];
+        let instructions = compile_with_options("include \"add.ez\" include \"add.ez\"", &options);
+
+        assert_eq!(instructions, vec![Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(1) }]);
+    }
+
+    #[test]
+    #[should_panic(expected = "creates a cycle")]
+    fn it_should_panic_on_include_cycles() {
+        let dir = create_files("cycle", &[("a.ez", "include \"b.ez\""), ("b.ez", "+ include \"a.ez\"")]);
+
+        let main_path = dir.join("a.ez");
+        let code = fs::read_to_string(&main_path).unwrap();
+        compile_with_source_map(&code, Some(&main_path), &CompileOptions::new(false));
+    }
+
+    #[test]
+    #[should_panic(expected = "Could not find included file \"missing.ez\" at line 1, column 3")]
+    fn it_should_panic_on_missing_files() {
+        compile_with_options("+ include \"missing.ez\"", &CompileOptions::new(false));
+    }
+}
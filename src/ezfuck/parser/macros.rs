@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+use crate::ezfuck::parser::expression::evaluate_expression;
+use crate::ezfuck::parser::parser::{describe_position, is_too_large_for_cell, is_zero_divisor, lex_between, InstructionValue, Source, Token, CALL_SYMBOL, DEFINITION_END_SYMBOL, DEFINITION_SYMBOL, JUMP_SYMBOL, LABEL_SYMBOL, MACRO_SYMBOL, MAX_CELL_ARGUMENT};
+
+struct Definition {
+    token: Token,
+    body: Vec<Token>,
+}
+
+struct Expander<'a> {
+    sources: &'a Vec<Source>,
+    definitions: HashMap<String, Definition>,
+    n_expansions: usize,
+    // The uses of the macros currently being expanded, innermost last.
+    uses: Vec<Token>,
+}
+
+impl Expander<'_> {
+    // Tokens from the body of a macro are positioned in its definition, so errors about them also say where the macro
+    //  was used.
+    fn describe_token_position(self: &Self, token: &Token) -> String {
+        let mut description = describe_position(&self.sources[token.file], token.position);
+        for use_token in self.uses.iter().rev() {
+            description += &format!(", in macro {} used at {}", use_token.name.as_ref().unwrap(), describe_position(&self.sources[use_token.file], use_token.position));
+        }
+
+        return description;
+    }
+
+    fn expand(self: &mut Self, tokens: &Vec<Token>, variables: &HashMap<String, usize>) -> Vec<Token> {
+        let mut expanded = vec![];
+        for token in tokens {
+            if token.instruction_symbol == MACRO_SYMBOL {
+                expanded.extend(self.expand_use(token, variables));
+            } else if token.expression.is_some() {
+                expanded.push(self.resolve_expression(token, variables));
+            } else {
+                expanded.push(token.clone());
+            }
+        }
+
+        return expanded;
+    }
+
+    fn expand_use(self: &mut Self, token: &Token, variables: &HashMap<String, usize>) -> Vec<Token> {
+        let name = token.name.clone().unwrap();
+        let (definition_position, parameters, body) = match self.definitions.get(&name) {
+            Some(definition) => {
+                let position = describe_position(&self.sources[definition.token.file], definition.token.position);
+                (position, definition.token.parameters.clone(), definition.body.clone())
+            },
+            // Strict mode already rejects names that aren't macros, so they're ignored here.
+            None => return self.expand_parenthesized(token, variables),
+        };
+
+        if self.uses.iter().any(|use_token| use_token.name.as_ref() == Some(&name)) {
+            panic!("Macro {name} defined at {definition_position} uses itself at {}", self.describe_token_position(token));
+        }
+
+        if token.arguments.len() != parameters.len() {
+            panic!(
+                "Macro {name} defined at {definition_position} takes {} argument(s), but was given {} at {}",
+                parameters.len(), token.arguments.len(), self.describe_token_position(token),
+            );
+        }
+
+        // Arguments are evaluated where the macro is used, so they can use the parameters of an enclosing macro.
+        let mut bindings = HashMap::new();
+        for (parameter, argument) in parameters.into_iter().zip(&token.arguments) {
+            match evaluate_expression(argument, variables) {
+                Ok(n) => bindings.insert(parameter, n),
+                Err(message) => panic!("{message}: {argument} given to macro {name} at {}", self.describe_token_position(token)),
+            };
+        }
+
+        let body = self.rename_labels(&body);
+        self.uses.push(token.clone());
+        let expanded = self.expand(&body, &bindings);
+        self.uses.pop();
+
+        return expanded;
+    }
+
+    // A name that isn't a macro is ignored, but the parentheses after it may still hold code, like the "+++" in "foo(+++)",
+    //  so that code is lexed like it would have been without the name.
+    fn expand_parenthesized(self: &mut Self, token: &Token, variables: &HashMap<String, usize>) -> Vec<Token> {
+        let open_i = token.position + token.name.as_ref().unwrap().len();
+        if token.end_position < open_i {
+            return vec![];
+        }
+
+        let parameters: Vec<String> = variables.keys().cloned().collect();
+        let is_in_macro = !self.uses.is_empty();
+        let tokens = lex_between(&self.sources[token.file], open_i + 1, token.end_position, is_in_macro.then_some(&parameters));
+        return self.expand(&tokens, variables);
+    }
+
+    // Labels defined in a macro are renamed each time it's expanded, so that using a macro twice doesn't define its labels
+    //  twice, and so that they can't collide with labels outside of it. "#" can't appear in names written in the
+    //  source, since it starts a comment.
+    fn rename_labels(self: &mut Self, body: &Vec<Token>) -> Vec<Token> {
+        self.n_expansions += 1;
+
+        let local_labels: HashSet<&String> = body.iter()
+            .filter(|token| token.instruction_symbol == LABEL_SYMBOL)
+            .filter_map(|token| token.name.as_ref())
+            .collect();
+
+        return body.iter().map(|token| {
+            let mut renamed = token.clone();
+            let is_label_symbol = [LABEL_SYMBOL, JUMP_SYMBOL, CALL_SYMBOL].contains(&token.instruction_symbol);
+            if let Some(name) = token.name.as_ref().filter(|name| is_label_symbol && local_labels.contains(name)) {
+                renamed.name = Some(format!("{name}#{}", self.n_expansions));
+            }
+            renamed
+        }).collect();
+    }
+
+    fn resolve_expression(self: &Self, token: &Token, variables: &HashMap<String, usize>) -> Token {
+        let expression = token.expression.as_ref().unwrap();
+        let n = match evaluate_expression(expression, variables) {
+            Ok(n) => n,
+            Err(message) => panic!("{message}: ({expression}) at {}", self.describe_token_position(token)),
+        };
+
+        if is_too_large_for_cell(token.instruction_symbol, n) {
+            panic!("{n} at {} is too large for a cell. Cell arguments can be at most {MAX_CELL_ARGUMENT}.", self.describe_token_position(token));
+        }
+        if is_zero_divisor(token.instruction_symbol, n) {
+            panic!("{} at {} divides by zero", token.instruction_symbol, self.describe_token_position(token));
+        }
+
+        let mut resolved = token.clone();
+        resolved.value = Some(InstructionValue::Number(n));
+        resolved.expression = None;
+        return resolved;
+    }
+}
+
+// Replaces each use of a macro with the tokens of its body, and removes the definitions. Macros can be used before
+//  they're defined, and can use other macros, but not themselves.
+pub fn expand_macros(sources: &Vec<Source>, tokens: Vec<Token>) -> Vec<Token> {
+    let mut definitions: HashMap<String, Definition> = HashMap::new();
+    let mut main_tokens = vec![];
+
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        if token.instruction_symbol != DEFINITION_SYMBOL {
+            main_tokens.push(token);
+            continue;
+        }
+
+        let body: Vec<Token> = tokens.by_ref().take_while(|body_token| body_token.instruction_symbol != DEFINITION_END_SYMBOL).collect();
+        let name = token.name.clone().unwrap();
+        if let Some(existing) = definitions.get(&name) {
+            let existing_position = describe_position(&sources[existing.token.file], existing.token.position);
+            panic!("Macro {name} at {} was already defined at {existing_position}", describe_position(&sources[token.file], token.position));
+        }
+
+        definitions.insert(name, Definition { token, body });
+    }
+
+    let mut expander = Expander { sources, definitions, n_expansions: 0, uses: vec![] };
+    return expander.expand(&main_tokens, &HashMap::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ezfuck::parser::parser::{compile_to_intermediate, compile_with_options, strict_options, Instruction, MathOperator};
+    use super::*;
+
+    #[test]
+    fn it_should_expand_macros_with_arguments() {
+        let code = "def add(n) { +n } def add_twice(n) { add(n) add(n * 2) } add_twice(3) add(4)";
+        let expected = compile_to_intermediate("+3 +6 +4", false);
+        assert_eq!(compile_to_intermediate(code, false), expected);
+    }
+
+    #[test]
+    fn it_should_allow_macros_to_be_used_before_they_are_defined() {
+        let code = "newline\ndef newline { ^10 . }";
+        assert_eq!(compile_to_intermediate(code, false), vec![
+            Instruction::SetCell { value: InstructionValue::Number(10) },
+            Instruction::PrintOut,
+        ]);
+    }
+
+    #[test]
+    fn it_should_keep_the_code_in_parentheses_after_names_that_arent_macros() {
+        let code = "def add(n) { plus(+n) } ^65 . foo(+++) . add(2) nothing()";
+        assert_eq!(compile_to_intermediate(code, false), compile_to_intermediate("^65 . +++ . +2", false));
+    }
+
+    #[test]
+    fn it_should_give_each_expansion_its_own_labels() {
+        let code = "{loop} def skip { {>end} + {end} } skip skip {>loop}";
+        let instructions = compile_to_intermediate(code, false);
+        assert_eq!(instructions, vec![
+            Instruction::Label,
+            Instruction::Jump { position: 3 },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(1) },
+            Instruction::Label,
+            Instruction::Jump { position: 6 },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(1) },
+            Instruction::Label,
+            Instruction::Jump { position: 0 },
+        ]);
+    }
+
+    #[test]
+    fn it_should_ignore_unknown_names_unless_strict() {
+        assert_eq!(compile_to_intermediate("+ plus one", false).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected character 'p' at line 1, column 3")]
+    fn it_should_panic_on_unknown_names_when_strict() {
+        compile_with_options("+ plus", &strict_options());
+    }
+
+    #[test]
+    #[should_panic(expected = "Macro a defined at line 1, column 1 uses itself at line 2, column 11, in macro b used at line 1, column 11, in macro a used at line 3, column 1")]
+    fn it_should_panic_on_recursive_macros() {
+        compile_to_intermediate("def a { + b }\ndef b { - a }\na", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "Macro add defined at line 1, column 1 takes 1 argument(s), but was given 2 at line 2, column 1")]
+    fn it_should_panic_when_given_the_wrong_number_of_arguments() {
+        compile_to_intermediate("def add(n) { +n }\nadd(1, 2)", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "300 at line 1, column 14, in macro add used at line 2, column 1 is too large for a cell")]
+    fn it_should_panic_when_an_argument_is_too_large_for_a_cell() {
+        compile_to_intermediate("def add(n) { +(n * 100) }\nadd(3)", false);
+    }
+}
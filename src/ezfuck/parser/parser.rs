@@ -1,7 +1,12 @@
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
 use strum_macros::Display;
+use crate::ezfuck::parser::expression::{evaluate_constant_expression, is_name_part, is_name_start};
+use crate::ezfuck::parser::macros::expand_macros;
+use crate::ezfuck::parser::include::resolve_includes;
 
 #[derive(Copy, Clone, Debug, Display, Eq, PartialEq)]
 pub enum EqualityOperator {
@@ -15,20 +20,39 @@ pub enum MathOperator {
     Subtraction,
     Multiplication,
     Division,
+    Modulo,
+    And,
+    Or,
+    Xor,
+    ShiftLeft,
+    ShiftRight,
 }
 
 #[derive(Copy, Clone, Debug, Display, Eq, PartialEq)]
 pub enum InstructionValue {
     CurrentCell,
-    Number(u8),
+    Number(usize),
+    // The value of the cell at an absolute position, like "@C".
+    Cell(usize),
+    // The value of the cell at an offset from the current cell, like "@>2".
+    RelativeCell(isize),
 }
 
 impl InstructionValue {
-    pub fn determine_value(self, current_cell_value: u8) -> u8 {
-        return match self {
-            InstructionValue::Number(n) => n,
-            InstructionValue::CurrentCell => current_cell_value,
-        }
+    pub fn determine_value(self, cells: &Vec<u8>, cell_ptr: usize) -> usize {
+        let cell_value = match self {
+            InstructionValue::Number(n) => return n,
+            InstructionValue::CurrentCell => cells[cell_ptr],
+            InstructionValue::Cell(i) => cells.get(i).copied().unwrap_or(0),
+            InstructionValue::RelativeCell(offset) => {
+                match cell_ptr.checked_add_signed(offset) {
+                    Some(i) => cells.get(i).copied().unwrap_or(0),
+                    None => panic!("Referenced a cell left of cell 0"),
+                }
+            },
+        };
+
+        return cell_value as usize;
     }
 }
 
@@ -44,9 +68,28 @@ pub enum Instruction {
     AddToCellPtr { direction: Direction, offset: InstructionValue },
     JumpToIf { position: usize, operator: EqualityOperator, match_value: u8 },
     PrintOut,
+    // Prints a byte of a string literal without going through a cell.
+    PrintConst { byte: u8 },
     ReadIn,
+    // Print the current cell as a decimal number, and parse a decimal number from the input into it. Only compiled when
+    //  numeric I/O is allowed.
+    PrintNumber,
+    ReadNumber,
     SetCell { value: InstructionValue },
+    SetCellPtr { position: usize },
     Breakpoint,
+    // Labels don't do anything themselves, but give jumps and calls somewhere to land.
+    Label,
+    Jump { position: usize },
+    // Like a jump, but pushes the current position onto the return stack first.
+    Call { position: usize },
+    Return,
+}
+
+impl Instruction {
+    pub fn reads_input(self: &Self) -> bool {
+        return matches!(self, Instruction::ReadIn | Instruction::ReadNumber);
+    }
 }
 
 impl Display for Instruction {
@@ -56,34 +99,183 @@ impl Display for Instruction {
             Instruction::AddToCellPtr { direction, offset } => format!("Move slots by {offset} {direction}"),
             Instruction::JumpToIf { position, operator, match_value } => format!("Jump to {position} when value {operator} {match_value}"),
             Instruction::PrintOut => "Print".to_string(),
+            Instruction::PrintConst { byte } => format!("Print {:?}", char::from(*byte)),
             Instruction::ReadIn => "Read".to_string(),
+            Instruction::PrintNumber => "Print Number".to_string(),
+            Instruction::ReadNumber => "Read Number".to_string(),
             Instruction::SetCell { value} => format!("Set Cell to {value}"),
+            Instruction::SetCellPtr { position } => format!("Move to slot {position}"),
             Instruction::Breakpoint => "Breakpoint".to_string(),
+            Instruction::Label => "Label".to_string(),
+            Instruction::Jump { position } => format!("Jump to {position}"),
+            Instruction::Call { position } => format!("Call {position}"),
+            Instruction::Return => "Return".to_string(),
         };
 
         return write!(f, "{}", output);
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SourcePosition {
+    // The index of the source in the source map that the position is in.
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for SourcePosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "line {}, column {}", self.line, self.column);
+    }
+}
+
+// The code of a single file. Lexing works on the characters of the code, so a source can be used like a Vec<char>.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Source {
+    // None for code that wasn't read from a file, like code entered into the REPL.
+    pub path: Option<PathBuf>,
+    // The position of the source in the source map. The main file is always 0.
+    pub index: usize,
+    pub chars: Vec<char>,
+    // Whether the numeric I/O symbols are lexed as instructions. Every source in a program shares the same setting.
+    pub allow_numeric_io: bool,
+}
+
+impl Source {
+    pub fn new(path: Option<PathBuf>, index: usize, code: &str, allow_numeric_io: bool) -> Source {
+        return Source { path, index, chars: code.chars().collect(), allow_numeric_io };
+    }
+
+    pub fn lines(self: &Self) -> Vec<String> {
+        return self.chars.iter().collect::<String>().lines().map(|line| line.to_string()).collect();
+    }
+}
+
+impl Deref for Source {
+    type Target = Vec<char>;
+
+    fn deref(&self) -> &Vec<char> {
+        return &self.chars;
+    }
+}
+
+// Every file that was compiled, and the position in them that each instruction was compiled from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceMap {
+    pub sources: Vec<Source>,
+    pub positions: Vec<SourcePosition>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompileOptions {
+    pub allow_debugging: bool,
+    // When strict, any character that isn't part of an instruction, a comment or whitespace is an error instead of being
+    //  ignored.
+    pub is_strict: bool,
+    // Directories that included files are searched for in, after the directory of the file including them.
+    pub include_paths: Vec<PathBuf>,
+    // Whether "=" and ";" print and read decimal numbers. They aren't part of the language, so they're ignored like any
+    //  other character unless this is enabled.
+    pub allow_numeric_io: bool,
+}
+
+impl CompileOptions {
+    pub fn new(allow_debugging: bool) -> CompileOptions {
+        return CompileOptions { allow_debugging, is_strict: false, include_paths: vec![], allow_numeric_io: false };
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Token {
-    instruction_symbol: char,
-    value: Option<InstructionValue>,
+    pub instruction_symbol: char,
+    pub value: Option<InstructionValue>,
+    // The name given to a label, jump, call, macro definition or macro use.
+    pub name: Option<String>,
+    // The parameters of a macro definition, and the arguments given to a macro use.
+    pub parameters: Vec<String>,
+    pub arguments: Vec<String>,
+    // An argument that uses the parameters of the macro it's in, so it can't be known until the macro is expanded.
+    pub expression: Option<String>,
+    // The index of the source the token is in, and its position in that source.
+    pub file: usize,
+    pub position: usize,
+    pub end_position: usize,
 }
 
 impl Token {
-    fn get_defaulted_value(self) -> InstructionValue {
+    fn new(instruction_symbol: char, position: usize, end_position: usize) -> Token {
+        return Token {
+            instruction_symbol,
+            value: None,
+            name: None,
+            parameters: vec![],
+            arguments: vec![],
+            expression: None,
+            file: 0,
+            position,
+            end_position,
+        };
+    }
+
+    fn get_defaulted_value(self: &Self) -> InstructionValue {
         return self.value.unwrap_or(InstructionValue::Number(1));
     }
 
-    fn has_value(self) -> bool {
+    fn has_value(self: &Self) -> bool {
         return self.value.is_some();
     }
 }
 
-const INSTRUCTION_SYMBOLS: &str = "+-*/<>[]^.,!";
-const VALUELESS_INSTRUCTION_SYMBOLS: &str = "[],.!";
-const VALUE_SYMBOLS: &str = "1234567890V";
+const INSTRUCTION_SYMBOLS: &str = "+-*/%&|~$<>[]^.,!@?{";
+const VALUELESS_INSTRUCTION_SYMBOLS: &str = "],.!{↷↳↲=;";
+// Only instructions when numeric I/O is allowed. "=" is used instead of ":" for printing, since ":" starts the commands
+//  of the REPL and the debugger.
+const NUMERIC_IO_SYMBOLS: &str = "=;";
+const PRINT_NUMBER_SYMBOL: char = '=';
+const READ_NUMBER_SYMBOL: char = ';';
+// Shifts are written as "$<" and "$>", but are lexed into a single symbol each.
+const SHIFT_SYMBOL: char = '$';
+const SHIFT_LEFT_SYMBOL: char = '«';
+const SHIFT_RIGHT_SYMBOL: char = '»';
+// Instructions that accept named cells and cell references as arguments.
+const ARGUMENT_SYMBOLS: &str = "+-*/%&|~«»^<>";
+// Instructions whose arguments are applied to or compared with a cell, so they have to fit in one.
+const CELL_VALUE_SYMBOLS: &str = "+-*/%&|~«»^[?";
+pub const MAX_CELL_ARGUMENT: usize = u8::MAX as usize;
+const MAX_IGNORED_BEFORE_VALUE: usize = 2;
+
+const CELL_NAME_SYMBOL: char = '@';
+// Written before a "[" to make a block that runs at most once, instead of a loop.
+const IF_SYMBOL: char = '?';
+
+// "{name}" marks a label, "{>name}" jumps to it, "{+name}" calls it, and "{}" returns from a call. Jumps and calls are
+//  lexed into a single symbol each, like shifts.
+pub const LABEL_SYMBOL: char = '{';
+const LABEL_END_SYMBOL: char = '}';
+pub const JUMP_SYMBOL: char = '↷';
+pub const CALL_SYMBOL: char = '↳';
+const RETURN_SYMBOL: char = '↲';
+
+// "def name(a, b) { ... }" defines a macro, and "name(1, 2)" uses it. Both are lexed into tokens, and macros are expanded
+//  before parsing.
+const DEFINITION_KEYWORD: &str = "def";
+pub const DEFINITION_SYMBOL: char = '≔';
+pub const DEFINITION_END_SYMBOL: char = '∎';
+pub const MACRO_SYMBOL: char = '⋯';
+
+// 'include "path/to/file.ez"' includes the code of another file in place of the include, the first time that file is
+//  included. Later includes of the same file are skipped, so that its macros and labels aren't defined twice.
+const INCLUDE_KEYWORD: &str = "include";
+pub const INCLUDE_SYMBOL: char = '⊂';
+
+// "Hello\n" prints each of its characters. Each character is lexed into its own token, so that each of them can be found
+//  in the source map.
+const STRING_SYMBOL: char = '"';
+
+const LINE_COMMENT_SYMBOL: char = '#';
+const BLOCK_COMMENT_START: [char; 2] = ['#', '|'];
+const BLOCK_COMMENT_END: [char; 2] = ['|', '#'];
 
 fn find_loop_indices(tokens: &Vec<Token>) -> (HashMap<usize, usize>, HashMap<usize, usize>) {
     let mut start_to_end: HashMap<usize, usize> = HashMap::new();
@@ -93,7 +285,7 @@ fn find_loop_indices(tokens: &Vec<Token>) -> (HashMap<usize, usize>, HashMap<usi
 
     for (i, token) in tokens.iter().enumerate() {
         let symbol = token.instruction_symbol;
-        if symbol == '[' {
+        if symbol == '[' || symbol == IF_SYMBOL {
             loop_start_stack.push(i);
         } else if symbol == ']' {
             let start_i = match loop_start_stack.pop() {
@@ -112,61 +304,590 @@ fn find_loop_indices(tokens: &Vec<Token>) -> (HashMap<usize, usize>, HashMap<usi
 
     return (start_to_end, end_to_start);
 }
+
+// Cells are named like spreadsheet columns: A to Z are cells 0 to 25, then AA is 26, AB is 27, and so on.
+pub fn produce_cell_name(cell_i: usize) -> String {
+    let mut name = vec![];
+    let mut remaining = cell_i;
+    loop {
+        name.push((b'A' + (remaining % 26) as u8) as char);
+        if remaining < 26 {
+            break;
+        }
+        remaining = remaining / 26 - 1;
+    }
+
+    return name.into_iter().rev().collect();
+}
+
+pub fn parse_cell_name(name: &str) -> Option<usize> {
+    if name.is_empty() || !name.chars().all(|letter| letter.is_ascii_uppercase()) {
+        return None;
+    }
+
+    let mut position: usize = 0;
+    for letter in name.bytes() {
+        position = position.checked_mul(26)?.checked_add((letter - b'A') as usize + 1)?;
+    }
+
+    return Some(position - 1);
+}
+
+// Returns the cell the name starting at start_i refers to, and the index of the name's last letter. Without a name, the
+//  "@" before it is ignored like any other unknown character.
+fn read_cell_name(code: &Source, start_i: usize) -> Option<(usize, usize)> {
+    let name: String = code[start_i.min(code.len())..].iter().take_while(|letter| letter.is_ascii_uppercase()).collect();
+    return match parse_cell_name(&name) {
+        Some(cell_i) => Some((cell_i, start_i + name.len() - 1)),
+        None if name.is_empty() => None,
+        None => panic!("Cell name {name} is too large"),
+    }
+}
+
+// Matches the "<2" or ">10" of a reference like "@<2" or "@>10", and returns it with the index of its last digit.
+//  References used to be written in brackets, but "+[>2]" is also a loop that moves right by 2.
+fn read_cell_reference(code: &Source, start_i: usize) -> Option<(InstructionValue, usize)> {
+    let direction = match code.get(start_i)? {
+        '<' => -1,
+        '>' => 1,
+        _ => return None,
+    };
+
+    let digits: String = code[start_i + 1..].iter().take_while(|digit| digit.is_ascii_digit()).collect();
+    if digits.is_empty() {
+        return None;
+    }
+
+    let end_i = start_i + digits.len();
+
+    return match digits.parse::<isize>() {
+        Ok(offset) => Some((InstructionValue::RelativeCell(offset * direction), end_i)),
+        Err(e) => panic!("Error parsing cell offset {digits}: {e:?}"),
+    }
+}
+
+// Besides plain values, instructions that take arguments can be given named cells and cell references.
+fn read_argument(code: &Source, start_i: usize) -> Option<(InstructionValue, usize)> {
+    if code.get(start_i) == Some(&CELL_NAME_SYMBOL) {
+        if let Some(reference) = read_cell_reference(code, start_i + 1) {
+            return Some(reference);
+        }
+
+        // Cell names are read in full, since unlike numbers, they aren't limited to a few characters.
+        let (cell_i, last_i) = read_cell_name(code, start_i + 1)?;
+        return Some((InstructionValue::Cell(cell_i), last_i));
+    }
+
+    return read_value(code, start_i);
+}
+
+// Returns the symbol the instruction starting at start_i is lexed as, and where its value would start. A "?" or "$" that
+//  isn't part of an instruction is ignored like any other unknown character.
+fn read_instruction_symbol(code: &Source, start_i: usize) -> Option<(char, usize)> {
+    let symbol = *code.get(start_i)?;
+    return match symbol {
+        // The value of an if-block is given after its "[", like "?[5".
+        IF_SYMBOL => match code.get(start_i + 1) {
+            Some('[') => Some((IF_SYMBOL, start_i + 2)),
+            _ => None,
+        },
+        SHIFT_SYMBOL => match code.get(start_i + 1) {
+            Some('<') => Some((SHIFT_LEFT_SYMBOL, start_i + 2)),
+            Some('>') => Some((SHIFT_RIGHT_SYMBOL, start_i + 2)),
+            _ => None,
+        },
+        LABEL_SYMBOL => match code.get(start_i + 1) {
+            Some('>') => Some((JUMP_SYMBOL, start_i + 2)),
+            Some('+') => Some((CALL_SYMBOL, start_i + 2)),
+            Some(&LABEL_END_SYMBOL) => Some((RETURN_SYMBOL, start_i + 2)),
+            _ => Some((LABEL_SYMBOL, start_i + 1)),
+        },
+        _ if is_instruction_symbol(code, symbol) => Some((symbol, start_i + 1)),
+        _ => None,
+    }
+}
+
+fn is_instruction_symbol(code: &Source, symbol: char) -> bool {
+    return INSTRUCTION_SYMBOLS.contains(symbol) || (code.allow_numeric_io && NUMERIC_IO_SYMBOLS.contains(symbol));
+}
+
+// Reads the name of a label, jump or call up to its closing "}", and returns it with the index of the "}". Names are made
+//  of letters, digits and underscores, and can't start with a digit.
+fn read_label_name(code: &Source, start_i: usize, label_start_i: usize) -> (String, usize) {
+    let name: String = code[start_i..].iter().take_while(|letter| letter.is_ascii_alphanumeric() || **letter == '_').collect();
+    let end_i = start_i + name.len();
+
+    let starts_with_digit = name.chars().next().is_some_and(|letter| letter.is_ascii_digit());
+    if name.is_empty() || starts_with_digit || code.get(end_i) != Some(&LABEL_END_SYMBOL) {
+        panic!("Expected a label name followed by }} at {}", describe_position(code, label_start_i));
+    }
+
+    return (name, end_i);
+}
+
+// Inside of a macro, arguments can use its parameters, either directly like "+n", or in an expression like "+(n*2)".
+//  Returns the argument and the index of its last character.
+fn read_parameter_expression(code: &Source, start_i: usize, parameters: &Vec<String>) -> Option<(String, usize)> {
+    return match *code.get(start_i)? {
+        letter if is_name_start(letter) => {
+            let (name, end_i) = read_name(code, start_i);
+            if parameters.contains(&name) { Some((name, end_i)) } else { None }
+        },
+        '(' => {
+            let end_i = expect_closing_parenthesis(code, start_i);
+            let expression: String = code[start_i + 1..end_i].iter().collect();
+            if expression.chars().any(is_name_start) { Some((expression, end_i)) } else { None }
+        },
+        _ => None,
+    }
+}
+
 // +3[>+4<-]
-fn get_token_and_advance(code: &Vec<char>, start_i: usize) -> Option<(Token, usize)> {
-    let mut instruction_symbol: Option<char> = None;
-    let mut raw_value: String = String::new();
-
-    let mut last_consumed_i: usize = start_i;
-    for i in start_i..start_i+4 {  // "+4" to account for the max length: +255
-        match code.get(i) {
-            Some(symbol) => {
-                if INSTRUCTION_SYMBOLS.contains(*symbol) {
-                    if instruction_symbol.is_none() {
-                        instruction_symbol = Some(*symbol);
-                        last_consumed_i = i;
-                    } else {
-                        break;
-                    }
-                } else if VALUE_SYMBOLS.contains(*symbol) && instruction_symbol.is_some() {
-                    raw_value.push(*symbol);
-                    last_consumed_i = i;
-                }
-            },
-            None => {
-                break;
+fn get_token_and_advance(code: &Source, start_i: usize, parameters: Option<&Vec<String>>) -> Option<(Token, usize)> {
+    let (symbol, value_start_i) = read_instruction_symbol(code, start_i)?;
+
+    if symbol == LABEL_SYMBOL || symbol == JUMP_SYMBOL || symbol == CALL_SYMBOL {
+        let (name, end_i) = read_label_name(code, value_start_i, start_i);
+        let mut token = Token::new(symbol, start_i, end_i);
+        token.name = Some(name);
+        return Some((token, end_i));
+    }
+
+    let parameter_expression = parameters.and_then(|parameters| read_parameter_expression(code, value_start_i, parameters));
+    if let Some((expression, end_i)) = parameter_expression {
+        let mut token = Token::new(symbol, start_i, end_i);
+        token.expression = Some(expression);
+        return Some((token, end_i));
+    }
+
+    let read = if symbol == CELL_NAME_SYMBOL {
+        let (cell_i, last_i) = read_cell_name(code, value_start_i)?;
+        Some((InstructionValue::Cell(cell_i), last_i))
+    } else if ARGUMENT_SYMBOLS.contains(symbol) {
+        read_argument(code, value_start_i).or_else(|| read_detached_value(code, value_start_i))
+    } else {
+        read_value(code, value_start_i)
+    };
+
+    let (value, last_consumed_i) = match read {
+        Some((value, end_i)) => (Some(value), end_i),
+        None => (None, value_start_i - 1),
+    };
+
+    if let Some(InstructionValue::Number(n)) = value {
+        if is_too_large_for_cell(symbol, n) {
+            panic!("{n} at {} is too large for a cell. Cell arguments can be at most {MAX_CELL_ARGUMENT}.", describe_position(code, start_i));
+        }
+        if is_zero_divisor(symbol, n) {
+            panic!("{symbol} at {} divides by zero", describe_position(code, start_i));
+        }
+    }
+
+    let mut token = Token::new(symbol, start_i, last_consumed_i);
+    token.value = value;
+    return Some((token, last_consumed_i));
+}
+
+// Values used to be read from anywhere in the three characters after a command, so older programs can write "+ 5" to mean
+//  "+5". When nothing directly follows a command, a number or "V" is still read after up to two ignored characters.
+fn read_detached_value(code: &Source, start_i: usize) -> Option<(InstructionValue, usize)> {
+    for value_i in start_i + 1..=start_i + MAX_IGNORED_BEFORE_VALUE {
+        if !is_ignored_before_value(code, *code.get(value_i - 1)?) {
+            return None;
+        }
+
+        let symbol = *code.get(value_i)?;
+        if symbol.is_ascii_digit() || symbol == 'V' {
+            return read_value(code, value_i);
+        }
+    }
+
+    return None;
+}
+
+// Names, strings and expressions aren't skipped over, so "+ n" or '+ "5"' keep their meaning.
+fn is_ignored_before_value(code: &Source, symbol: char) -> bool {
+    return !is_instruction_symbol(code, symbol) && !is_name_part(symbol) && symbol != STRING_SYMBOL && symbol != '(';
+}
+
+pub fn is_too_large_for_cell(symbol: char, n: usize) -> bool {
+    return CELL_VALUE_SYMBOLS.contains(symbol) && n > MAX_CELL_ARGUMENT;
+}
+
+// Zero divisors that come from cells can only be found when running the program.
+pub fn is_zero_divisor(symbol: char, n: usize) -> bool {
+    return (symbol == '/' || symbol == '%') && n == 0;
+}
+
+// Returns the name starting at start_i, and the index of its last character.
+fn read_name(code: &Source, start_i: usize) -> (String, usize) {
+    let name: String = code[start_i..].iter().take_while(|letter| is_name_part(**letter)).collect();
+    return (name.clone(), start_i + name.len() - 1);
+}
+
+// Names are only read from their start, so that the "x" in "0x" or "Ax" isn't mistaken for one.
+fn is_start_of_name(code: &Source, i: usize) -> bool {
+    return is_name_start(code[i]) && (i == 0 || !is_name_part(code[i - 1]));
+}
+
+// Splits "1, n + 2" into "1" and "n + 2". Expressions can't contain commas, so there's no need to track parentheses.
+fn split_arguments(arguments: &str) -> Vec<String> {
+    if arguments.trim().is_empty() {
+        return vec![];
+    }
+
+    return arguments.split(',').map(|argument| argument.trim().to_string()).collect();
+}
+
+// A use of a macro, like "name" or "name(3, 4)". Outside of strict mode, names that aren't macros are ignored like any
+//  other unknown characters when the macros are expanded. A "(" that's never closed isn't part of the use.
+fn read_macro_use(code: &Source, start_i: usize) -> (Token, usize) {
+    let (name, name_end_i) = read_name(code, start_i);
+    let close_i = match code.get(name_end_i + 1) {
+        Some('(') => find_closing_parenthesis(code, name_end_i + 1),
+        _ => None,
+    };
+    let (arguments, end_i) = match close_i {
+        Some(close_i) => (split_arguments(&code[name_end_i + 2..close_i].iter().collect::<String>()), close_i),
+        None => (vec![], name_end_i),
+    };
+
+    let mut token = Token::new(MACRO_SYMBOL, start_i, end_i);
+    token.name = Some(name);
+    token.arguments = arguments;
+    return (token, end_i);
+}
+
+// Reads 'include "path"' into a token holding the path. Without a path, "include" is just a word, like in a comment.
+fn read_include(code: &Source, start_i: usize) -> Option<(Token, usize)> {
+    let open_i = skip_whitespace(code, start_i + INCLUDE_KEYWORD.len());
+    let close_i = match code.get(open_i) {
+        Some('"') => code[open_i + 1..].iter().position(|symbol| *symbol == '"' || *symbol == '\n').map(|offset| open_i + 1 + offset),
+        _ => None,
+    };
+
+    return match close_i {
+        Some(close_i) if code[close_i] == '"' => {
+            let mut token = Token::new(INCLUDE_SYMBOL, start_i, close_i);
+            token.name = Some(code[open_i + 1..close_i].iter().collect());
+            Some((token, close_i))
+        },
+        _ => None,
+    }
+}
+
+// Returns the index of the quote that closes the string literal opened at open_i. Strings can't span lines.
+fn find_string_end(code: &Source, open_i: usize) -> Option<usize> {
+    let mut i = open_i + 1;
+    while i < code.len() && code[i] != '\n' {
+        match code[i] {
+            STRING_SYMBOL => return Some(i),
+            '\\' => i += 2,
+            _ => i += 1,
+        }
+    }
+
+    return None;
+}
+
+// Replaces the contents of string literals with spaces, so that symbols in them aren't mistaken for code.
+fn blank_strings(code: &Source) -> Source {
+    let mut blanked = code.clone();
+
+    let mut i = 0;
+    while i < code.len() {
+        if code[i] != STRING_SYMBOL {
+            i += 1;
+            continue;
+        }
+
+        let Some(close_i) = find_string_end(code, i) else {
+            return blanked;
+        };
+        blanked.chars[i + 1..close_i].fill(' ');
+        i = close_i + 1;
+    }
+
+    return blanked;
+}
+
+// Reads the character at i, which may be escaped. Returns its bytes in UTF-8, with the index of its last character.
+//  "\x" escapes give a single byte, so that strings can also print bytes that aren't text.
+fn read_string_character(code: &Source, i: usize) -> (Vec<u8>, usize) {
+    if code[i] != '\\' {
+        return (code[i].to_string().into_bytes(), i);
+    }
+
+    return match code[i + 1] {
+        'n' => (vec![b'\n'], i + 1),
+        't' => (vec![b'\t'], i + 1),
+        'r' => (vec![b'\r'], i + 1),
+        '0' => (vec![0], i + 1),
+        '\\' => (vec![b'\\'], i + 1),
+        '"' => (vec![b'"'], i + 1),
+        'x' => {
+            let digits: String = code.get(i + 2..i + 4).map_or(String::new(), |digits| digits.iter().collect());
+            match u8::from_str_radix(&digits, 16) {
+                Ok(byte) => (vec![byte], i + 3),
+                Err(_) => panic!("\\x must be followed by two hex digits at {}", describe_position(code, i)),
             }
+        },
+        escaped => panic!("Unknown escape \\{escaped} at {}", describe_position(code, i)),
+    }
+}
+
+// Reads a string literal into a token for each byte it prints. The first token includes the opening quote and the last
+//  includes the closing quote, so that the quotes count as part of the string in strict mode. A quote that's never closed
+//  is ignored like any other unknown character, and an empty string prints nothing.
+fn read_string(code: &Source, start_i: usize) -> Option<(Vec<Token>, usize)> {
+    let close_i = find_string_end(code, start_i)?;
+    if close_i == start_i + 1 {
+        return Some((vec![], close_i));
+    }
+
+    let mut tokens = vec![];
+    let mut i = start_i + 1;
+    while i < close_i {
+        let (bytes, end_i) = read_string_character(code, i);
+        for byte in bytes {
+            let mut token = Token::new(STRING_SYMBOL, i, end_i);
+            token.value = Some(InstructionValue::Number(byte as usize));
+            tokens.push(token);
         }
+        i = end_i + 1;
     }
 
-    return match instruction_symbol {
-        Some(symbol) => {
-            let mut value: Option<InstructionValue> = None;
-            if raw_value.len() > 0 {
-                if raw_value == "V" {
-                    value = Some(InstructionValue::CurrentCell);
-                } else {
-                    value = match raw_value.parse::<u8>() {
-                        Ok(parsed) => Some(InstructionValue::Number(parsed)),
-                        Err(e) => {
-                            panic!("Error parsing value {raw_value}: {e:?}");
-                        }
-                    }
-                }
+    tokens.first_mut().unwrap().position = start_i;
+    tokens.last_mut().unwrap().end_position = close_i;
+    return Some((tokens, close_i));
+}
+
+fn skip_whitespace(code: &Source, start_i: usize) -> usize {
+    return start_i + code[start_i..].iter().take_while(|symbol| symbol.is_whitespace()).count();
+}
+
+fn find_definition_end(code: &Source, open_i: usize, name: &str, start_i: usize) -> usize {
+    let mut depth = 0;
+    for (i, symbol) in blank_strings(code).iter().enumerate().skip(open_i) {
+        if *symbol == LABEL_SYMBOL {
+            depth += 1;
+        } else if *symbol == LABEL_END_SYMBOL {
+            depth -= 1;
+            if depth == 0 {
+                return i;
+            }
+        }
+    }
+
+    panic!("Macro {name} defined at {} is never closed", describe_position(code, start_i));
+}
+
+// Reads "def name(a, b) { ... }" into a definition token, the tokens of its body, and a token marking the end of the
+//  body. Returns them with the index of the closing "}". Without a name and a "{", "def" is just a word, like in a comment.
+fn read_definition(code: &Source, start_i: usize) -> Option<(Vec<Token>, usize)> {
+    let name_i = skip_whitespace(code, start_i + DEFINITION_KEYWORD.len());
+    if !code.get(name_i).is_some_and(|letter| is_name_start(*letter)) {
+        return None;
+    }
+    let (name, name_end_i) = read_name(code, name_i);
+
+    let (raw_parameters, header_end_i) = match code.get(name_end_i + 1) {
+        Some('(') => {
+            let close_i = find_closing_parenthesis(code, name_end_i + 1)?;
+            (code[name_end_i + 2..close_i].iter().collect::<String>(), close_i)
+        },
+        _ => (String::new(), name_end_i),
+    };
+
+    let open_i = skip_whitespace(code, header_end_i + 1);
+    if code.get(open_i) != Some(&LABEL_SYMBOL) {
+        return None;
+    }
+
+    let parameters = split_arguments(&raw_parameters);
+    for parameter in &parameters {
+        if !parameter.starts_with(is_name_start) || !parameter.chars().all(is_name_part) {
+            panic!("Invalid parameter name {parameter:?} for macro {name} at {}", describe_position(code, start_i));
+        }
+    }
+    let close_i = find_definition_end(code, open_i, &name, start_i);
+
+    let mut definition = Token::new(DEFINITION_SYMBOL, start_i, open_i);
+    definition.name = Some(name);
+    definition.parameters = parameters;
+
+    let mut tokens = vec![];
+    let body = lex_range(code, open_i + 1, close_i, Some(&definition.parameters));
+    tokens.push(definition);
+    tokens.extend(body);
+    tokens.push(Token::new(DEFINITION_END_SYMBOL, close_i, close_i));
+    return Some((tokens, close_i));
+}
+
+fn find_closing_parenthesis(code: &Source, start_i: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, symbol) in code.iter().enumerate().skip(start_i) {
+        if *symbol == '(' {
+            depth += 1;
+        } else if *symbol == ')' {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
             }
+        }
+    }
 
-            let token = Token { instruction_symbol: symbol, value: value };
-            Some((token, last_consumed_i))
+    return None;
+}
+
+// Expressions are always code, so unlike after a name, an unclosed "(" after an instruction is an error.
+fn expect_closing_parenthesis(code: &Source, start_i: usize) -> usize {
+    return match find_closing_parenthesis(code, start_i) {
+        Some(close_i) => close_i,
+        None => panic!("( missing a matching ) at {}", describe_position(code, start_i)),
+    }
+}
+
+// Reads the argument given directly after an instruction, and returns it with the index of its last character. Arguments
+//  are a number, "V", or a constant expression in parentheses.
+fn read_value(code: &Source, start_i: usize) -> Option<(InstructionValue, usize)> {
+    return match *code.get(start_i)? {
+        'V' => Some((InstructionValue::CurrentCell, start_i)),
+        '(' => {
+            let end_i = expect_closing_parenthesis(code, start_i);
+            let expression: String = code[start_i + 1..end_i].iter().collect();
+            match evaluate_constant_expression(&expression) {
+                Ok(n) => Some((InstructionValue::Number(n), end_i)),
+                Err(message) => panic!("{message}: ({expression}) at {}", describe_position(code, start_i)),
+            }
         },
-        None => None,
+        digit if digit.is_ascii_digit() => {
+            let raw_value: String = code[start_i..].iter().take_while(|digit| digit.is_ascii_digit()).collect();
+            match raw_value.parse::<usize>() {
+                Ok(parsed) => Some((InstructionValue::Number(parsed), start_i + raw_value.len() - 1)),
+                Err(e) => panic!("Error parsing value {raw_value}: {e:?}"),
+            }
+        },
+        _ => None,
+    }
+}
+
+fn find_block_comment_end(code: &Source, start_i: usize) -> usize {
+    let mut i = start_i + BLOCK_COMMENT_START.len();
+    while i + 1 < code.len() {
+        if code[i..i + 2] == BLOCK_COMMENT_END {
+            return i + 1;
+        }
+        i += 1;
+    }
+
+    panic!("Block comment starting at {} is never closed", describe_position(code, start_i));
+}
+
+// Comments are replaced with spaces instead of being removed so that the positions of the remaining characters don't
+//  change. "#" comments out the rest of the line, and "#|" comments out everything up to the next "|#".
+fn blank_comments(code: &Source) -> Source {
+    let mut blanked = code.clone();
+
+    let mut i = 0;
+    while i < code.len() {
+        // A "#" in a string is part of the string.
+        if code[i] == STRING_SYMBOL {
+            i = find_string_end(code, i).map_or(i + 1, |close_i| close_i + 1);
+            continue;
+        }
+
+        if code[i] != LINE_COMMENT_SYMBOL {
+            i += 1;
+            continue;
+        }
+
+        let after_end_i = if code.get(i..i + 2) == Some(&BLOCK_COMMENT_START[..]) {
+            find_block_comment_end(code, i) + 1
+        } else {
+            code[i..].iter().position(|symbol| *symbol == '\n').map_or(code.len(), |offset| i + offset)
+        };
+
+        for symbol in &mut blanked.chars[i..after_end_i] {
+            if *symbol != '\n' {
+                *symbol = ' ';
+            }
+        }
+
+        i = after_end_i;
     }
+
+    return blanked;
+}
+
+pub fn lex(code: &Source) -> Vec<Token> {
+    return lex_between(code, 0, code.len(), None);
+}
+
+// Lexes part of a source, like the code in the parentheses after a name that isn't a macro.
+pub fn lex_between(code: &Source, start_i: usize, end_i: usize, parameters: Option<&Vec<String>>) -> Vec<Token> {
+    let code = &blank_comments(code);
+
+    let mut tokens = lex_range(code, start_i, end_i, parameters);
+    for token in &mut tokens {
+        token.file = code.index;
+    }
+
+    return tokens;
 }
 
-fn lex(code: &Vec<char>) -> Vec<Token> {
+// Lexes the code between start_i and end_i. Parameters are given when lexing the body of a macro.
+fn lex_range(code: &Source, start_i: usize, end_i: usize, parameters: Option<&Vec<String>>) -> Vec<Token> {
     let mut tokens: Vec<Token> = vec![];
-    let mut current_token_i = 0;
-    while current_token_i < code.len() {
-        match get_token_and_advance(code, current_token_i) {
+    let mut current_token_i = start_i;
+    while current_token_i < end_i {
+        if is_start_of_name(code, current_token_i) {
+            let (name, _) = read_name(code, current_token_i);
+            let keyword_tokens = if name == INCLUDE_KEYWORD {
+                read_include(code, current_token_i).map(|(token, last_i)| (vec![token], last_i))
+            } else if name == DEFINITION_KEYWORD {
+                read_definition(code, current_token_i)
+            } else {
+                None
+            };
+
+            let (name_tokens, last_i) = match keyword_tokens {
+                Some(_) if parameters.is_some() && name == INCLUDE_KEYWORD => {
+                    panic!("Files can't be included inside of macros, at {}", describe_position(code, current_token_i));
+                },
+                Some(_) if parameters.is_some() => {
+                    panic!("Macros can't be defined inside of other macros, at {}", describe_position(code, current_token_i));
+                },
+                Some(keyword_tokens) => keyword_tokens,
+                None => {
+                    let (token, last_i) = read_macro_use(code, current_token_i);
+                    (vec![token], last_i)
+                },
+            };
+
+            tokens.extend(name_tokens);
+            current_token_i = last_i + 1;
+            continue;
+        }
+
+        if code[current_token_i] == STRING_SYMBOL {
+            current_token_i = match read_string(code, current_token_i) {
+                Some((string_tokens, last_i)) => {
+                    tokens.extend(string_tokens);
+                    last_i + 1
+                },
+                None => current_token_i + 1,
+            };
+            continue;
+        }
+
+        // Tokens are read starting from their symbol, so that characters before it don't count towards its length.
+        if !is_instruction_symbol(code, code[current_token_i]) {
+            current_token_i += 1;
+            continue;
+        }
+
+        match get_token_and_advance(code, current_token_i, parameters) {
             Some((token, i)) => {
                 tokens.push(token);
                 current_token_i = i + 1;
@@ -180,19 +901,105 @@ fn lex(code: &Vec<char>) -> Vec<Token> {
     return tokens;
 }
 
-fn assert_valueless(token: Token) {
+// Every character outside of a comment has to be whitespace, or part of a token.
+fn assert_strictly_valid(sources: &Vec<Source>, tokens: &Vec<Token>) -> () {
+    // Names that aren't macros are ignored outside of strict mode, so they don't count as being part of a token. Macros
+    //  can be defined in any of the sources.
+    let macro_names: HashSet<&String> = tokens.iter()
+        .filter(|token| token.instruction_symbol == DEFINITION_SYMBOL)
+        .filter_map(|token| token.name.as_ref())
+        .collect();
+
+    for source in sources {
+        let code = blank_comments(source);
+
+        let mut is_consumed = vec![false; code.len()];
+        for token in tokens.iter().filter(|token| token.file == source.index) {
+            if token.instruction_symbol == MACRO_SYMBOL && !token.name.as_ref().is_some_and(|name| macro_names.contains(name)) {
+                continue;
+            }
+            is_consumed[token.position..=token.end_position].fill(true);
+        }
+
+        for (i, symbol) in code.iter().enumerate() {
+            if !symbol.is_whitespace() && !is_consumed[i] {
+                panic!("Unexpected character {symbol:?} at {}", describe_position(&code, i));
+            }
+        }
+    }
+}
+
+fn assert_valueless(token: &Token) {
     if token.has_value() {
         panic!("Command {:?} cannot be given a value. Given {:?}.", token.instruction_symbol, token.value);
     }
 }
 
-fn parse(tokens: Vec<Token>, allow_debugging: bool) -> Vec<Instruction> {
+// Loops and if-blocks only compare against numbers, since the comparison is part of the jump instruction.
+fn find_match_value(token: &Token) -> u8 {
+    return match token.value {
+        None => 0,
+        Some(InstructionValue::Number(n)) => n as u8,
+        Some(value) => panic!("Loops can only be given numbers. Given {value:?}."),
+    }
+}
+
+// Returns whether the token is compiled into an instruction. Jump positions are found using this, since they're
+//  positions in the compiled instructions, not in the tokens.
+fn produces_instruction(i: usize, tokens: &Vec<Token>, end_to_start: &HashMap<usize, usize>, allow_debugging: bool) -> bool {
+    return match tokens[i].instruction_symbol {
+        '!' => allow_debugging,
+        // An if-block doesn't need to jump back to its start once it's finished.
+        ']' => tokens[end_to_start[&i]].instruction_symbol != IF_SYMBOL,
+        _ => true,
+    }
+}
+
+// Maps each label's name to the index of its token.
+fn find_label_indices(sources: &Vec<Source>, tokens: &Vec<Token>) -> HashMap<String, usize> {
+    let mut label_indices: HashMap<String, usize> = HashMap::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.instruction_symbol != LABEL_SYMBOL {
+            continue;
+        }
+
+        let name = token.name.clone().unwrap();
+        if let Some(existing_i) = label_indices.insert(name.clone(), i) {
+            let existing_position = describe_position(&sources[tokens[existing_i].file], tokens[existing_i].position);
+            panic!("Label {name} at {} was already defined at {existing_position}", describe_position(&sources[token.file], token.position));
+        }
+    }
+
+    return label_indices;
+}
+
+fn find_label_target(sources: &Vec<Source>, token: &Token, label_indices: &HashMap<String, usize>, instruction_indices: &Vec<usize>) -> usize {
+    let name = token.name.as_ref().unwrap();
+    return match label_indices.get(name) {
+        Some(label_i) => instruction_indices[*label_i],
+        None => panic!("Label {name} used at {} is never defined", describe_position(&sources[token.file], token.position)),
+    }
+}
+
+fn parse<'a>(sources: &Vec<Source>, tokens: &'a Vec<Token>, allow_debugging: bool) -> Vec<(Instruction, &'a Token)> {
     let mut instructions = Vec::new();
 
     let (start_to_end, end_to_start) = find_loop_indices(&tokens);
+    let label_indices = find_label_indices(sources, tokens);
+
+    // The index that each token's instruction will have once compiled.
+    let mut instruction_indices = vec![];
+    let mut n_instructions = 0;
+    for i in 0..tokens.len() {
+        instruction_indices.push(n_instructions);
+        if produces_instruction(i, tokens, &end_to_start, allow_debugging) {
+            n_instructions += 1;
+        }
+    }
+
     for (i, token) in tokens.iter().enumerate() {
         if VALUELESS_INSTRUCTION_SYMBOLS.contains(token.instruction_symbol) {
-            assert_valueless(*token);
+            assert_valueless(token);
         }
 
         let defaulted_value = token.get_defaulted_value();
@@ -201,25 +1008,55 @@ fn parse(tokens: Vec<Token>, allow_debugging: bool) -> Vec<Instruction> {
             '-' => Some(Instruction::ApplyOperatorToCell { operator: MathOperator::Subtraction, value: defaulted_value }),
             '*' => Some(Instruction::ApplyOperatorToCell { operator: MathOperator::Multiplication, value: defaulted_value }),
             '/' => Some(Instruction::ApplyOperatorToCell { operator: MathOperator::Division, value: defaulted_value }),
+            '%' => Some(Instruction::ApplyOperatorToCell { operator: MathOperator::Modulo, value: defaulted_value }),
+            '&' => Some(Instruction::ApplyOperatorToCell { operator: MathOperator::And, value: defaulted_value }),
+            '|' => Some(Instruction::ApplyOperatorToCell { operator: MathOperator::Or, value: defaulted_value }),
+            '~' => Some(Instruction::ApplyOperatorToCell { operator: MathOperator::Xor, value: defaulted_value }),
+            SHIFT_LEFT_SYMBOL => Some(Instruction::ApplyOperatorToCell { operator: MathOperator::ShiftLeft, value: defaulted_value }),
+            SHIFT_RIGHT_SYMBOL => Some(Instruction::ApplyOperatorToCell { operator: MathOperator::ShiftRight, value: defaulted_value }),
             '<' => Some(Instruction::AddToCellPtr { direction: Direction::Left, offset: defaulted_value }),
             '>' => Some(Instruction::AddToCellPtr { direction: Direction::Right, offset: defaulted_value }),
             '[' => {
-                let end_i = start_to_end.get(&i).unwrap();
-                Some(Instruction::JumpToIf { position: *end_i, operator: EqualityOperator::Equal, match_value: 0 })
+                let end_i = start_to_end[&i];
+                Some(Instruction::JumpToIf { position: instruction_indices[end_i], operator: EqualityOperator::Equal, match_value: find_match_value(token) })
+            },
+            IF_SYMBOL => {
+                // The "]" of an if-block isn't compiled, so this jumps to the instruction before whatever follows it,
+                //  since the instruction pointer is advanced after jumping.
+                let end_i = start_to_end[&i];
+                Some(Instruction::JumpToIf { position: instruction_indices[end_i] - 1, operator: EqualityOperator::Equal, match_value: find_match_value(token) })
             },
             ']' => {
-                let start_i = end_to_start.get(&i).unwrap();
-                Some(Instruction::JumpToIf { position: *start_i, operator: EqualityOperator::NotEqual, match_value: 0 })
+                let start_i = end_to_start[&i];
+                if tokens[start_i].instruction_symbol == IF_SYMBOL {
+                    None
+                } else {
+                    Some(Instruction::JumpToIf { position: instruction_indices[start_i], operator: EqualityOperator::NotEqual, match_value: find_match_value(&tokens[start_i]) })
+                }
             },
             '.' => Some(Instruction::PrintOut),
+            STRING_SYMBOL => match token.value {
+                Some(InstructionValue::Number(byte)) => Some(Instruction::PrintConst { byte: byte as u8 }),
+                _ => None,
+            },
             ',' => Some(Instruction::ReadIn),
+            PRINT_NUMBER_SYMBOL => Some(Instruction::PrintNumber),
+            READ_NUMBER_SYMBOL => Some(Instruction::ReadNumber),
             '^' => Some(Instruction::SetCell { value: defaulted_value }),
             '!' => if allow_debugging { Some(Instruction::Breakpoint) } else { None },
+            '@' => match token.value {
+                Some(InstructionValue::Cell(position)) => Some(Instruction::SetCellPtr { position }),
+                _ => panic!("{CELL_NAME_SYMBOL} must be followed by a cell name"),
+            },
+            LABEL_SYMBOL => Some(Instruction::Label),
+            JUMP_SYMBOL => Some(Instruction::Jump { position: find_label_target(sources, token, &label_indices, &instruction_indices) }),
+            CALL_SYMBOL => Some(Instruction::Call { position: find_label_target(sources, token, &label_indices, &instruction_indices) }),
+            RETURN_SYMBOL => Some(Instruction::Return),
             _ => None,
         };
 
         match instruction {
-            Some(inst) => instructions.push(inst),
+            Some(inst) => instructions.push((inst, token)),
             None => (),
         }
     }
@@ -227,13 +1064,90 @@ fn parse(tokens: Vec<Token>, allow_debugging: bool) -> Vec<Instruction> {
     return instructions;
 }
 
+pub fn find_source_position(code: &Source, char_i: usize) -> SourcePosition {
+    let mut position = SourcePosition { file: code.index, line: 1, column: 1 };
+    for symbol in &code[..char_i] {
+        if *symbol == '\n' {
+            position.line += 1;
+            position.column = 1;
+        } else {
+            position.column += 1;
+        }
+    }
+
+    return position;
+}
+
+// Positions in errors include the path of the file they're in, since they could be in any included file.
+pub fn describe_position(code: &Source, char_i: usize) -> String {
+    let position = find_source_position(code, char_i);
+    return match &code.path {
+        Some(path) => format!("{}, {position}", path.display()),
+        None => position.to_string(),
+    }
+}
+
+// Returns how many loops and macro definitions are left open at the end of the code, or None if a ] is missing its [.
+pub fn count_unclosed_loops(code: &str) -> Option<usize> {
+    let code_vec = Source::new(None, 0, code, false);
+
+    // Checked before lexing, since an unclosed macro definition can't be lexed.
+    let blanked = blank_strings(&blank_comments(&code_vec));
+    let n_opened = blanked.iter().filter(|symbol| **symbol == LABEL_SYMBOL).count();
+    let n_closed = blanked.iter().filter(|symbol| **symbol == LABEL_END_SYMBOL).count();
+    if n_opened > n_closed {
+        return Some(n_opened - n_closed);
+    }
+
+    let mut depth: usize = 0;
+    for token in lex(&code_vec) {
+        if token.instruction_symbol == '[' || token.instruction_symbol == IF_SYMBOL {
+            depth += 1;
+        } else if token.instruction_symbol == ']' {
+            depth = depth.checked_sub(1)?;
+        }
+    }
+
+    return Some(depth);
+}
+
+// A shorthand for tests, which mostly only care about whether breakpoints are allowed.
+#[cfg(test)]
 pub fn compile_to_intermediate(code: &str, allow_debugging: bool) -> Vec<Instruction> {
-    let code_vec: Vec<char> = code.chars().collect();
-    let tokens = lex(&code_vec);
-    let instructions = parse(tokens, allow_debugging);
+    return compile_with_options(code, &CompileOptions::new(allow_debugging));
+}
+
+#[cfg(test)]
+pub fn strict_options() -> CompileOptions {
+    return CompileOptions { is_strict: true, ..CompileOptions::new(false) };
+}
+
+pub fn compile_with_options(code: &str, options: &CompileOptions) -> Vec<Instruction> {
+    let (instructions, _) = compile_with_source_map(code, None, options);
     return instructions;
 }
 
+// The path is where the code was read from, if anywhere, and is used to find files that it includes. The source map
+//  holds the 1-based line and column in the source that each instruction was compiled from.
+pub fn compile_with_source_map(code: &str, path: Option<&Path>, options: &CompileOptions) -> (Vec<Instruction>, SourceMap) {
+    let main_source = Source::new(path.map(|path| path.to_path_buf()), 0, code, options.allow_numeric_io);
+    let (tokens, sources) = resolve_includes(main_source, &options.include_paths);
+
+    if options.is_strict {
+        assert_strictly_valid(&sources, &tokens);
+    }
+
+    let tokens: Vec<Token> = tokens.into_iter().filter(|token| token.instruction_symbol != INCLUDE_SYMBOL).collect();
+    let tokens = expand_macros(&sources, tokens);
+
+    let (instructions, positions): (Vec<Instruction>, Vec<SourcePosition>) = parse(&sources, &tokens, options.allow_debugging)
+        .into_iter()
+        .map(|(instruction, token)| (instruction, find_source_position(&sources[token.file], token.position)))
+        .unzip();
+
+    return (instructions, SourceMap { sources, positions });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -306,4 +1220,297 @@ mod tests {
         let code = "+]-";
         compile_to_intermediate(code, false);
     }
+
+    #[test]
+    fn it_should_map_each_instruction_to_its_line_and_column() {
+        let code = "+5\n  >2 -";
+        let (instructions, source_map) = compile_with_source_map(code, None, &CompileOptions::new(false));
+
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(source_map.positions, vec![
+            SourcePosition { file: 0, line: 1, column: 1 },
+            SourcePosition { file: 0, line: 2, column: 3 },
+            SourcePosition { file: 0, line: 2, column: 6 },
+        ]);
+    }
+
+    #[test]
+    fn it_should_count_unclosed_loops() {
+        assert_eq!(count_unclosed_loops("+[->[-]"), Some(1));
+        assert_eq!(count_unclosed_loops("+[-]"), Some(0));
+        assert_eq!(count_unclosed_loops("]["), None);
+    }
+
+    #[test]
+    fn it_should_read_values_after_a_run_of_ignored_characters() {
+        let instructions = compile_to_intermediate("    +25", false);
+        assert_eq!(instructions, vec![Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(25) }]);
+    }
+
+    #[test]
+    fn it_should_read_values_separated_from_their_command_by_up_to_two_characters() {
+        let instructions = compile_to_intermediate("+ 5 -\tV *  3 /   4", false);
+
+        assert_eq!(instructions, vec![
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(5) },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Subtraction, value: InstructionValue::CurrentCell },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Multiplication, value: InstructionValue::Number(3) },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Division, value: InstructionValue::Number(1) },
+        ]);
+    }
+
+    #[test]
+    fn it_should_ignore_line_and_block_comments() {
+        let code = "+2 # Add ^V*3 here\n#\n#| Multiply\n  by */ three |# *3";
+        let (instructions, source_map) = compile_with_source_map(code, None, &CompileOptions::new(false));
+
+        assert_eq!(instructions, vec![
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(2) },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Multiplication, value: InstructionValue::Number(3) },
+        ]);
+        assert_eq!(source_map.positions[1], SourcePosition { file: 0, line: 4, column: 18 });
+    }
+
+    #[test]
+    #[should_panic(expected = "never closed")]
+    fn it_should_panic_on_an_unclosed_block_comment() {
+        compile_to_intermediate("+ #| -", false);
+    }
+
+    #[test]
+    fn it_should_allow_commands_comments_and_whitespace_in_strict_mode() {
+        let options = strict_options();
+        let instructions = compile_with_options("+5 >V\n[-] # Clear", &options);
+
+        assert_eq!(instructions.len(), 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected character 'x' at line 2, column 2")]
+    fn it_should_panic_on_other_characters_in_strict_mode() {
+        let options = strict_options();
+        compile_with_options("+5\n x", &options);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected character '3'")]
+    fn it_should_panic_on_values_without_a_command_in_strict_mode() {
+        let options = strict_options();
+        compile_with_options("+ 3 3", &options);
+    }
+
+    #[test]
+    fn it_should_convert_between_cell_names_and_positions() {
+        for (name, position) in [("A", 0), ("Z", 25), ("AA", 26), ("AZ", 51), ("BA", 52), ("ZZ", 701), ("AAA", 702)] {
+            assert_eq!(produce_cell_name(position), name);
+            assert_eq!(parse_cell_name(name), Some(position));
+        }
+
+        assert_eq!(parse_cell_name("a"), None);
+        assert_eq!(parse_cell_name(""), None);
+    }
+
+    #[test]
+    fn it_should_compile_named_cells() {
+        let instructions = compile_to_intermediate("@C +@AB >2 @V", false);
+
+        assert_eq!(instructions, vec![
+            Instruction::SetCellPtr { position: 2 },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Cell(27) },
+            Instruction::AddToCellPtr { direction: Direction::Right, offset: InstructionValue::Number(2) },
+            Instruction::SetCellPtr { position: 21 },
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected character '@' at line 1, column 2")]
+    fn it_should_panic_on_a_missing_cell_name_in_strict_mode() {
+        compile_with_options("+@ 3", &strict_options());
+    }
+
+    #[test]
+    fn it_should_compile_relative_cell_references() {
+        let instructions = compile_to_intermediate("+@>2 ^@<10 +[>2]", false);
+
+        assert_eq!(instructions, vec![
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::RelativeCell(2) },
+            Instruction::SetCell { value: InstructionValue::RelativeCell(-10) },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(1) },
+            Instruction::JumpToIf { position: 5, operator: EqualityOperator::Equal, match_value: 0 },
+            Instruction::AddToCellPtr { direction: Direction::Right, offset: InstructionValue::Number(2) },
+            Instruction::JumpToIf { position: 3, operator: EqualityOperator::NotEqual, match_value: 0 },
+        ]);
+    }
+
+    #[test]
+    fn it_should_allow_large_pointer_offsets_and_expression_arguments() {
+        let instructions = compile_to_intermediate(">300 +(3*7) <(2 * (100 + 50))", false);
+
+        assert_eq!(instructions, vec![
+            Instruction::AddToCellPtr { direction: Direction::Right, offset: InstructionValue::Number(300) },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(21) },
+            Instruction::AddToCellPtr { direction: Direction::Left, offset: InstructionValue::Number(300) },
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "too large for a cell")]
+    fn it_should_panic_on_cell_arguments_that_dont_fit_in_a_cell() {
+        compile_to_intermediate("+(200 + 56)", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "( missing a matching )")]
+    fn it_should_panic_on_an_unclosed_expression() {
+        compile_to_intermediate("+(3*7", false);
+    }
+
+    #[test]
+    fn it_should_compile_loops_that_compare_against_a_number() {
+        let instructions = compile_to_intermediate("[10 - ]", false);
+
+        assert_eq!(instructions, vec![
+            Instruction::JumpToIf { position: 2, operator: EqualityOperator::Equal, match_value: 10 },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Subtraction, value: InstructionValue::Number(1) },
+            Instruction::JumpToIf { position: 0, operator: EqualityOperator::NotEqual, match_value: 10 },
+        ]);
+    }
+
+    #[test]
+    fn it_should_compile_if_blocks_without_a_jump_back() {
+        let instructions = compile_to_intermediate("?[5 + ] .", false);
+
+        assert_eq!(instructions, vec![
+            Instruction::JumpToIf { position: 1, operator: EqualityOperator::Equal, match_value: 5 },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(1) },
+            Instruction::PrintOut,
+        ]);
+    }
+
+    #[test]
+    fn it_should_jump_to_instructions_instead_of_tokens_when_breakpoints_are_removed() {
+        let instructions = compile_to_intermediate("! [ ! ]", false);
+
+        assert_eq!(instructions, vec![
+            Instruction::JumpToIf { position: 1, operator: EqualityOperator::Equal, match_value: 0 },
+            Instruction::JumpToIf { position: 0, operator: EqualityOperator::NotEqual, match_value: 0 },
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "% at line 2, column 3 divides by zero")]
+    fn it_should_panic_on_dividing_by_a_literal_zero() {
+        compile_to_intermediate("/5\n+ %(3 - 3)", false);
+    }
+
+    #[test]
+    fn it_should_compile_modulo_and_bitwise_operators() {
+        let instructions = compile_to_intermediate("%3 &V |@B ~@>1 $<2 $>", false);
+        let operators: Vec<(MathOperator, InstructionValue)> = instructions.iter().map(|instruction| match instruction {
+            Instruction::ApplyOperatorToCell { operator, value } => (*operator, *value),
+            _ => panic!("Expected a cell operation, got {instruction:?}"),
+        }).collect();
+
+        assert_eq!(operators, vec![
+            (MathOperator::Modulo, InstructionValue::Number(3)),
+            (MathOperator::And, InstructionValue::CurrentCell),
+            (MathOperator::Or, InstructionValue::Cell(1)),
+            (MathOperator::Xor, InstructionValue::RelativeCell(1)),
+            (MathOperator::ShiftLeft, InstructionValue::Number(2)),
+            (MathOperator::ShiftRight, InstructionValue::Number(1)),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected character '$' at line 1, column 1")]
+    fn it_should_panic_on_a_shift_without_a_direction_in_strict_mode() {
+        compile_with_options("$3", &strict_options());
+    }
+
+    #[test]
+    fn it_should_ignore_symbols_that_arent_part_of_an_instruction() {
+        let instructions = compile_to_intermediate("^65 . Why? . Costs $5 @ most. +@ 3", false);
+
+        assert_eq!(instructions, vec![
+            Instruction::SetCell { value: InstructionValue::Number(65) },
+            Instruction::PrintOut,
+            Instruction::PrintOut,
+            Instruction::PrintOut,
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(1) },
+        ]);
+    }
+
+    #[test]
+    fn it_should_ignore_keywords_and_quotes_in_prose() {
+        let code = "+ include this or def it and def main(ish - \"\" and \" unclosed and call(";
+        let instructions = compile_to_intermediate(code, false);
+
+        assert_eq!(instructions, vec![
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(1) },
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Subtraction, value: InstructionValue::Number(1) },
+        ]);
+    }
+
+    #[test]
+    fn it_should_compile_labels_jumps_and_calls() {
+        let instructions = compile_to_intermediate("{>end} {start} + {} {+start} {end_2} {end} {>start}", false);
+
+        assert_eq!(instructions, vec![
+            Instruction::Jump { position: 6 },
+            Instruction::Label,
+            Instruction::ApplyOperatorToCell { operator: MathOperator::Addition, value: InstructionValue::Number(1) },
+            Instruction::Return,
+            Instruction::Call { position: 1 },
+            Instruction::Label,
+            Instruction::Label,
+            Instruction::Jump { position: 1 },
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Label loop at line 2, column 1 was already defined at line 1, column 1")]
+    fn it_should_panic_on_duplicate_labels() {
+        compile_to_intermediate("{loop}\n{loop}", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "Label missing used at line 1, column 3 is never defined")]
+    fn it_should_panic_on_undefined_labels() {
+        compile_to_intermediate("+ {+missing}", false);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a label name followed by }")]
+    fn it_should_panic_on_malformed_labels() {
+        compile_to_intermediate("{>1abc}", false);
+    }
+
+    #[test]
+    fn it_should_compile_strings_into_printed_bytes() {
+        let instructions = compile_to_intermediate("+ \"a# {\\n\\x41\\\"\" -", false);
+        let bytes: Vec<Instruction> = b"a# {\nA\"".iter().map(|byte| Instruction::PrintConst { byte: *byte }).collect();
+        assert_eq!(instructions[1..instructions.len() - 1], bytes[..]);
+        assert_eq!(instructions.len(), bytes.len() + 2);
+    }
+
+    #[test]
+    fn it_should_accept_strings_in_strict_mode_and_map_each_byte_to_its_character() {
+        let options = strict_options();
+        let (instructions, source_map) = compile_with_source_map("\"a\\tb\"", None, &options);
+        assert_eq!(instructions.len(), 3);
+        let columns: Vec<usize> = source_map.positions.iter().map(|position| position.column).collect();
+        assert_eq!(columns, vec![1, 3, 5]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected character '\"' at line 1, column 3")]
+    fn it_should_panic_on_unclosed_strings_in_strict_mode() {
+        compile_with_options("+ \"abc\n\"", &strict_options());
+    }
+
+    #[test]
+    fn it_should_print_the_utf8_bytes_of_characters_in_strings() {
+        let bytes: Vec<Instruction> = [0xe2, 0x86, 0x92, 0xff].into_iter().map(|byte| Instruction::PrintConst { byte }).collect();
+        assert_eq!(compile_to_intermediate("\"→\\xff\"", false), bytes);
+    }
 }
\ No newline at end of file
@@ -1,2 +1,4 @@
 pub mod repl;
-pub mod cell_repr;
\ No newline at end of file
+pub mod cell_repr;
+pub mod line_editor;
+pub mod commands;
\ No newline at end of file
@@ -1,28 +1,591 @@
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::collections::HashSet;
+use std::io::IsTerminal;
+use std::ops::RangeInclusive;
+use crate::ezfuck::interpreter::interpreter::ExecutionState;
+use crate::ezfuck::parser::parser::produce_cell_name;
 
-pub fn produce_cells_repr(cells: &Vec<u8>, cell_ptr: usize) -> String {
-    let mut last_i = cells.iter().rposition(|cell| *cell != 0).unwrap_or(0);
-    last_i = max(last_i, cell_ptr);
+pub const DEFAULT_WINDOW_WIDTH: usize = 16;
 
-    return if cells.len() > 0 {
-        let mut ptr_row: String = String::from("  ");
-        let mut index_row: String = String::from("i ");
-        let mut raw_row: String = String::from("d ");
-        let mut ascii_row: String = String::from("a ");
+// Cells past the end of the tape are all 0, so a pinned range only shows this many of them. Otherwise, pinning a huge
+//  range would show every cell in it on every render.
+const MAX_PINNED_CELLS_PAST_TAPE: usize = 64;
+
+const POINTER_STYLE: &str = "7";
+const IO_STYLE: &str = "4;36";
+const CHANGED_STYLE: &str = "1;33";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NumberFormat {
+    Decimal,
+    Hex,
+    Binary,
+    Signed,
+}
+
+impl NumberFormat {
+    fn parse(raw_format: &str) -> Option<NumberFormat> {
+        return match raw_format {
+            "dec" | "decimal" => Some(NumberFormat::Decimal),
+            "hex" => Some(NumberFormat::Hex),
+            "bin" | "binary" => Some(NumberFormat::Binary),
+            "signed" => Some(NumberFormat::Signed),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> char {
+        return match self {
+            NumberFormat::Decimal => 'd',
+            NumberFormat::Hex => 'x',
+            NumberFormat::Binary => 'b',
+            NumberFormat::Signed => 's',
+        }
+    }
+
+    fn width(self) -> usize {
+        return match self {
+            NumberFormat::Decimal => 3,
+            NumberFormat::Hex => 4,
+            NumberFormat::Binary => 8,
+            NumberFormat::Signed => 4,
+        }
+    }
+
+    fn format(self, cell_value: u8) -> String {
+        return match self {
+            NumberFormat::Decimal => format!("{cell_value:0>3}"),
+            NumberFormat::Hex => format!("0x{cell_value:02X}"),
+            NumberFormat::Binary => format!("{cell_value:08b}"),
+            NumberFormat::Signed => format!("{:>4}", cell_value as i8),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CharFormat {
+    Ascii,
+    Escaped,
+    Utf8,
+}
+
+impl CharFormat {
+    fn parse(raw_format: &str) -> Option<CharFormat> {
+        return match raw_format {
+            "ascii" => Some(CharFormat::Ascii),
+            "escaped" => Some(CharFormat::Escaped),
+            "utf8" => Some(CharFormat::Utf8),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> char {
+        return match self {
+            CharFormat::Ascii => 'a',
+            CharFormat::Escaped => 'e',
+            CharFormat::Utf8 => 'u',
+        }
+    }
+
+    fn width(self) -> usize {
+        return match self {
+            CharFormat::Ascii | CharFormat::Utf8 => 1,
+            CharFormat::Escaped => 4,
+        }
+    }
+
+    fn format(self, cells: &Vec<u8>, i: usize) -> String {
+        let cell_value = cells.get(i).copied().unwrap_or(0);
+        return match self {
+            CharFormat::Ascii => {
+                let cell_ascii = if cell_value >= 32 { cell_value as char } else { ' ' };
+                cell_ascii.to_string()
+            },
+            CharFormat::Escaped => escape_cell_value(cell_value),
+            CharFormat::Utf8 => decode_utf8_at(cells, i),
+        }
+    }
+}
+
+fn escape_cell_value(cell_value: u8) -> String {
+    return match cell_value {
+        b'\n' => "\\n".to_string(),
+        b'\t' => "\\t".to_string(),
+        b'\r' => "\\r".to_string(),
+        0 => "\\0".to_string(),
+        b'\\' => "\\\\".to_string(),
+        32..=126 => (cell_value as char).to_string(),
+        _ => format!("\\x{cell_value:02X}"),
+    }
+}
+
+fn find_utf8_sequence_length(lead_byte: u8) -> Option<usize> {
+    return match lead_byte {
+        0x00..=0x7F => Some(1),
+        0xC0..=0xDF => Some(2),
+        0xE0..=0xEF => Some(3),
+        0xF0..=0xF7 => Some(4),
+        _ => None,
+    }
+}
+
+fn decode_utf8_sequence(cells: &Vec<u8>, start_i: usize) -> Option<char> {
+    let length = find_utf8_sequence_length(*cells.get(start_i)?)?;
+    let sequence = cells.get(start_i..start_i + length)?;
+    return std::str::from_utf8(sequence).ok()?.chars().next();
+}
+
+// Multi-byte characters are shown on the cell holding their first byte, and the cells holding the rest of the bytes are
+//  marked with "~". Bytes that aren't part of valid UTF-8 are shown as "?".
+fn decode_utf8_at(cells: &Vec<u8>, i: usize) -> String {
+    let cell_value = cells.get(i).copied().unwrap_or(0);
+    if cell_value < 32 || cell_value == 127 {
+        return " ".to_string();
+    }
+
+    if let Some(decoded) = decode_utf8_sequence(cells, i) {
+        return decoded.to_string();
+    }
+
+    let is_continuation = (1..=3)
+        .filter_map(|back| i.checked_sub(back).map(|start_i| (back, start_i)))
+        .any(|(back, start_i)| {
+            let length = cells.get(start_i).copied().and_then(find_utf8_sequence_length).unwrap_or(0);
+            length > back && decode_utf8_sequence(cells, start_i).is_some()
+        });
+
+    return if is_continuation { "~".to_string() } else { "?".to_string() };
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TapeView {
+    // How many cells around the cell pointer are shown. None shows every cell.
+    pub window_width: Option<usize>,
+    // Ranges of cells that are always shown, even when they're outside of the window.
+    pub pinned_ranges: Vec<RangeInclusive<usize>>,
+    // How many characters wide a row can be before it wraps. None uses the width of the terminal.
+    pub wrap_width: Option<usize>,
+    pub number_format: NumberFormat,
+    pub char_format: CharFormat,
+    pub use_color: bool,
+    // Whether each cell's name (like "AB") is shown under its index.
+    pub show_names: bool,
+    // What the cells looked like the last time they were shown, so that changed cells can be highlighted.
+    pub last_displayed_cells: Option<Vec<u8>>,
+}
+
+impl TapeView {
+    pub fn new() -> TapeView {
+        return TapeView {
+            window_width: Some(DEFAULT_WINDOW_WIDTH),
+            pinned_ranges: vec![],
+            wrap_width: None,
+            number_format: NumberFormat::Decimal,
+            char_format: CharFormat::Ascii,
+            use_color: false,
+            show_names: true,
+            last_displayed_cells: None,
+        };
+    }
+
+    // How wide the values in each column are, not counting the separators.
+    fn find_value_width(self: &Self) -> usize {
+        return max(max(self.number_format.width(), self.char_format.width()), 3);
+    }
+
+    fn find_visible_indices(self: &Self, cells: &Vec<u8>, cell_ptr: usize) -> Vec<usize> {
+        let mut last_i = cells.iter().rposition(|cell| *cell != 0).unwrap_or(0);
+        last_i = max(last_i, cell_ptr);
+
+        let mut visible: Vec<usize> = match self.window_width {
+            Some(width) => {
+                let start = cell_ptr.saturating_sub(width / 2);
+                let end = min(start + max(width, 1) - 1, last_i);
+                (start..=end).collect()
+            },
+            None => (0..=last_i).collect(),
+        };
+
+        for range in &self.pinned_ranges {
+            let last_shown = max(*range.start(), cells.len()).saturating_add(MAX_PINNED_CELLS_PAST_TAPE - 1);
+            visible.extend(*range.start()..=min(*range.end(), last_shown));
+        }
+
+        visible.sort();
+        visible.dedup();
+        return visible;
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Column {
+    Cell(usize),
+    Ellipsis,
+}
+
+fn find_columns(visible_indices: &Vec<usize>, cells: &Vec<u8>, cell_ptr: usize) -> Vec<Column> {
+    let last_i = max(cells.iter().rposition(|cell| *cell != 0).unwrap_or(0), cell_ptr);
+
+    let mut columns = vec![];
+    let mut expected_i = 0;
+    for i in visible_indices {
+        if *i != expected_i {
+            columns.push(Column::Ellipsis);
+        }
+
+        columns.push(Column::Cell(*i));
+        expected_i = i.saturating_add(1);
+    }
+
+    if expected_i <= last_i {
+        columns.push(Column::Ellipsis);
+    }
+
+    return columns;
+}
+
+// Colors are only used when writing to a terminal, and never when NO_COLOR is set (https://no-color.org).
+pub fn should_use_color() -> bool {
+    let is_color_disabled = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    return std::io::stdout().is_terminal() && !is_color_disabled;
+}
+
+fn find_cell_style(i: usize, cells: &Vec<u8>, cell_ptr: usize, view: &TapeView, io_cells: &HashSet<usize>) -> String {
+    let mut codes = vec![];
+    if i == cell_ptr {
+        codes.push(POINTER_STYLE);
+    }
+
+    if io_cells.contains(&i) {
+        codes.push(IO_STYLE);
+    }
+
+    if let Some(last_displayed_cells) = &view.last_displayed_cells {
+        let last_value = last_displayed_cells.get(i).copied().unwrap_or(0);
+        if cells.get(i).copied().unwrap_or(0) != last_value {
+            codes.push(CHANGED_STYLE);
+        }
+    }
+
+    return codes.join(";");
+}
 
-        for i in 0..=last_i {
-            let cell_value = cells[i];
-            let cell_ascii = if cell_value >= 32 { cell_value as char } else { ' ' };
-            let ptr_repr = if i == cell_ptr { "   V  " } else { "      " };
+fn colorize(text: String, style: &str) -> String {
+    return if style.is_empty() { text } else { format!("\x1b[{style}m{text}\x1b[0m") };
+}
 
-            ptr_row.push_str(ptr_repr);
-            index_row.push_str(format!("| {i:0>3} ").as_str());
-            raw_row.push_str(format!("| {cell_value:0>3} ").as_str());
-            ascii_row.push_str(format!("|  {cell_ascii}  ").as_str());
+fn produce_columns_repr(columns: &[Column], cells: &Vec<u8>, cell_ptr: usize, view: &TapeView, io_cells: &HashSet<usize>) -> String {
+    let width = view.find_value_width();
+
+    let mut ptr_row: String = String::from("  ");
+    let mut index_row: String = String::from("i ");
+    let mut name_row: String = String::from("n ");
+    let mut raw_row: String = format!("{} ", view.number_format.label());
+    let mut char_row: String = format!("{} ", view.char_format.label());
+
+    for column in columns {
+        match column {
+            Column::Cell(i) => {
+                let cell_value = cells.get(*i).copied().unwrap_or(0);
+                let ptr_repr = if *i == cell_ptr { "V" } else { " " };
+                let index_repr = format!("{i:0>3}");
+                let name_repr = produce_cell_name(*i);
+                let raw_repr = view.number_format.format(cell_value);
+                let char_repr = view.char_format.format(cells, *i);
+                let style = if view.use_color { find_cell_style(*i, cells, cell_ptr, view, io_cells) } else { String::new() };
+
+                ptr_row.push_str(format!("  {ptr_repr:^width$} ").as_str());
+                index_row.push_str(format!("| {} ", colorize(format!("{index_repr:^width$}"), &style)).as_str());
+                name_row.push_str(format!("| {} ", colorize(format!("{name_repr:^width$}"), &style)).as_str());
+                raw_row.push_str(format!("| {} ", colorize(format!("{raw_repr:^width$}"), &style)).as_str());
+                char_row.push_str(format!("| {} ", colorize(format!("{char_repr:^width$}"), &style)).as_str());
+            },
+            Column::Ellipsis => {
+                ptr_row.push_str(format!("  {:width$} ", "").as_str());
+                index_row.push_str(format!("| {:^width$} ", "...").as_str());
+                name_row.push_str(format!("| {:^width$} ", "...").as_str());
+                raw_row.push_str(format!("| {:^width$} ", "...").as_str());
+                char_row.push_str(format!("| {:^width$} ", "...").as_str());
+            },
         }
+    }
+
+    let name_line = if view.show_names { format!("{name_row}|\n") } else { String::new() };
+    return format!("{ptr_row}\n{index_row}|\n{name_line}{raw_row}|\n{char_row}|\n");
+}
+
+fn find_terminal_width() -> Option<usize> {
+    return crossterm::terminal::size().ok().map(|(width, _)| width as usize);
+}
+
+pub fn produce_cells_repr(cells: &Vec<u8>, cell_ptr: usize, view: &TapeView, io_cells: &HashSet<usize>) -> String {
+    return if cells.len() > 0 {
+        let visible_indices = view.find_visible_indices(cells, cell_ptr);
+        let columns = find_columns(&visible_indices, cells, cell_ptr);
+
+        // Each row starts with a 2 character label, and ends with a "|". Each column is surrounded by "| " and " ".
+        let column_width = view.find_value_width() + 3;
+        let columns_per_row = match view.wrap_width.or_else(find_terminal_width) {
+            Some(wrap_width) => max(wrap_width.saturating_sub(3) / column_width, 1),
+            None => columns.len(),
+        };
 
-        format!("{ptr_row}\n{index_row}|\n{raw_row}|\n{ascii_row}|\n")
+        columns.chunks(max(columns_per_row, 1))
+            .map(|row_columns| produce_columns_repr(row_columns, cells, cell_ptr, view, io_cells))
+            .collect()
     } else {
         String::new()
     }
+}
+
+// Also remembers what was shown, so that the next repr can highlight what changed since this one.
+pub fn produce_state_cells_repr(state: &mut ExecutionState) -> String {
+    let repr = produce_cells_repr(&state.cells, state.cell_ptr, &state.tape_view, &state.io_cells);
+    state.tape_view.last_displayed_cells = Some(state.cells.clone());
+    state.io_cells.clear();
+
+    return repr;
+}
+
+fn parse_range(raw_range: &str) -> Option<RangeInclusive<usize>> {
+    return match raw_range.split_once('-') {
+        Some((raw_start, raw_end)) => {
+            let start = raw_start.trim().parse::<usize>().ok()?;
+            let end = raw_end.trim().parse::<usize>().ok()?;
+            if start <= end { Some(start..=end) } else { None }
+        },
+        None => {
+            let i = raw_range.trim().parse::<usize>().ok()?;
+            Some(i..=i)
+        },
+    }
+}
+
+fn parse_switch(argument: &str) -> Option<bool> {
+    return match argument {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+// Returns None if the command isn't a view command. Shared by the REPL and the debugger.
+pub fn handle_view_command(name: &str, argument: &str, view: &mut TapeView) -> Option<Result<(), String>> {
+    let argument = argument.trim();
+    let result = match name {
+        ":view" => {
+            if argument == "all" {
+                view.window_width = None;
+                Ok(())
+            } else {
+                match argument.parse::<usize>() {
+                    Ok(width) if width > 0 => {
+                        view.window_width = Some(width);
+                        Ok(())
+                    },
+                    _ => Err("Expected :view <number of cells> or :view all".to_string()),
+                }
+            }
+        },
+        ":pin" => {
+            match parse_range(argument) {
+                Some(range) => {
+                    view.pinned_ranges.push(range);
+                    Ok(())
+                },
+                None => Err("Expected :pin <cell> or :pin <first cell>-<last cell>".to_string()),
+            }
+        },
+        ":unpin" => {
+            view.pinned_ranges.clear();
+            Ok(())
+        },
+        ":color" => {
+            match parse_switch(argument) {
+                Some(use_color) => {
+                    view.use_color = use_color;
+                    Ok(())
+                },
+                None => Err("Expected :color on or :color off".to_string()),
+            }
+        },
+        ":names" => {
+            match parse_switch(argument) {
+                Some(show_names) => {
+                    view.show_names = show_names;
+                    Ok(())
+                },
+                None => Err("Expected :names on or :names off".to_string()),
+            }
+        },
+        ":format" => {
+            match NumberFormat::parse(argument) {
+                Some(number_format) => {
+                    view.number_format = number_format;
+                    Ok(())
+                },
+                None => Err("Expected :format dec, :format hex, :format bin or :format signed".to_string()),
+            }
+        },
+        ":chars" => {
+            match CharFormat::parse(argument) {
+                Some(char_format) => {
+                    view.char_format = char_format;
+                    Ok(())
+                },
+                None => Err("Expected :chars ascii, :chars escaped or :chars utf8".to_string()),
+            }
+        },
+        _ => return None,
+    };
+
+    return Some(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view_with_window(window_width: Option<usize>) -> TapeView {
+        let mut view = TapeView::new();
+        view.window_width = window_width;
+        view.wrap_width = Some(1000);
+        return view;
+    }
+
+    #[test]
+    fn it_should_show_every_cell_up_to_the_last_non_zero_cell() {
+        let repr = produce_cells_repr(&vec![1, 0, 65, 0], 0, &view_with_window(None), &HashSet::new());
+
+        assert_eq!(repr, "     V              \ni | 000 | 001 | 002 |\nn |  A  |  B  |  C  |\nd | 001 | 000 | 065 |\na |     |     |  A  |\n");
+    }
+
+    #[test]
+    fn it_should_elide_cells_outside_of_the_window() {
+        let mut cells = vec![0; 300];
+        cells[0] = 1;
+        cells[299] = 2;
+
+        let repr = produce_cells_repr(&cells, 150, &view_with_window(Some(3)), &HashSet::new());
+        let index_row = repr.lines().nth(1).unwrap();
+
+        assert_eq!(index_row, "i | ... | 149 | 150 | 151 | ... |");
+        assert_eq!(repr.lines().nth(2).unwrap(), "n | ... | ET  | EU  | EV  | ... |");
+    }
+
+    #[test]
+    fn it_should_show_pinned_ranges_outside_of_the_window() {
+        let mut view = view_with_window(Some(1));
+        view.pinned_ranges.push(5..=6);
+
+        let repr = produce_cells_repr(&vec![0; 10], 0, &view, &HashSet::new());
+        let index_row = repr.lines().nth(1).unwrap();
+
+        assert_eq!(index_row, "i | 000 | ... | 005 | 006 |");
+    }
+
+    #[test]
+    fn it_should_only_show_part_of_a_huge_pinned_range_past_the_tape() {
+        let mut view = view_with_window(Some(1));
+        view.pinned_ranges.push(0..=1_000_000_000);
+        view.pinned_ranges.push(5000..=5001);
+
+        let visible = view.find_visible_indices(&vec![0; 10], 0);
+        assert_eq!(visible.len(), 10 + MAX_PINNED_CELLS_PAST_TAPE + 2);
+        assert_eq!(visible.last(), Some(&5001));
+    }
+
+    #[test]
+    fn it_should_show_a_pinned_cell_at_the_largest_index() {
+        let mut view = view_with_window(Some(1));
+        assert_eq!(handle_view_command(":pin", &usize::MAX.to_string(), &mut view), Some(Ok(())));
+
+        let repr = produce_cells_repr(&vec![0; 10], 0, &view, &HashSet::new());
+        let index_row = repr.lines().nth(1).unwrap();
+
+        assert_eq!(index_row, format!("i | 000 | ... | {} |", usize::MAX));
+    }
+
+    #[test]
+    fn it_should_wrap_rows_that_are_too_wide() {
+        let mut view = view_with_window(None);
+        view.wrap_width = Some(15);
+
+        let repr = produce_cells_repr(&vec![1, 2, 3], 0, &view, &HashSet::new());
+        let index_rows: Vec<&str> = repr.lines().filter(|line| line.starts_with("i ")).collect();
+
+        assert_eq!(index_rows, vec!["i | 000 | 001 |", "i | 002 |"]);
+    }
+
+    #[test]
+    fn it_should_parse_view_commands() {
+        let mut view = TapeView::new();
+
+        assert_eq!(handle_view_command(":view", "all", &mut view), Some(Ok(())));
+        assert_eq!(view.window_width, None);
+
+        assert_eq!(handle_view_command(":pin", "300-310", &mut view), Some(Ok(())));
+        assert_eq!(view.pinned_ranges, vec![300..=310]);
+
+        assert!(handle_view_command(":pin", "10-5", &mut view).unwrap().is_err());
+        assert_eq!(handle_view_command(":names", "off", &mut view), Some(Ok(())));
+        assert!(!view.show_names);
+
+        assert_eq!(handle_view_command(":reset", "", &mut view), None);
+    }
+
+    #[test]
+    fn it_should_widen_columns_to_fit_the_number_format() {
+        let mut view = view_with_window(None);
+        view.number_format = NumberFormat::Binary;
+
+        let repr = produce_cells_repr(&vec![5, 200], 1, &view, &HashSet::new());
+
+        assert_eq!(repr, "                  V     \ni |   000    |   001    |\nn |    A     |    B     |\nb | 00000101 | 11001000 |\na |          |    È     |\n");
+    }
+
+    #[test]
+    fn it_should_format_numbers_as_signed_and_hex() {
+        assert_eq!(NumberFormat::Signed.format(255), "  -1");
+        assert_eq!(NumberFormat::Hex.format(255), "0xFF");
+    }
+
+    #[test]
+    fn it_should_escape_control_characters() {
+        let cells = vec![b'\n', b'\t', 0, 27, b'A'];
+        let escaped: Vec<String> = (0..cells.len()).map(|i| CharFormat::Escaped.format(&cells, i)).collect();
+
+        assert_eq!(escaped, vec!["\\n", "\\t", "\\0", "\\x1B", "A"]);
+    }
+
+    #[test]
+    fn it_should_decode_utf8_sequences_spread_across_cells() {
+        let mut cells = "é!".as_bytes().to_vec();
+        cells.push(0xFF);
+        let decoded: Vec<String> = (0..cells.len()).map(|i| CharFormat::Utf8.format(&cells, i)).collect();
+
+        assert_eq!(decoded, vec!["é", "~", "!", "?"]);
+    }
+
+    #[test]
+    fn it_should_highlight_the_pointer_changed_cells_and_io_cells() {
+        let mut view = view_with_window(None);
+        view.use_color = true;
+        view.last_displayed_cells = Some(vec![1, 2, 3]);
+
+        let repr = produce_cells_repr(&vec![1, 5, 3], 0, &view, &HashSet::from([2]));
+        let raw_row = repr.lines().nth(3).unwrap();
+
+        assert_eq!(raw_row, "d | \x1b[7m001\x1b[0m | \x1b[1;33m005\x1b[0m | \x1b[4;36m003\x1b[0m |");
+    }
+
+    #[test]
+    fn it_should_not_use_color_unless_enabled() {
+        let mut view = view_with_window(None);
+        view.last_displayed_cells = Some(vec![0]);
+
+        let repr = produce_cells_repr(&vec![1], 0, &view, &HashSet::new());
+
+        assert!(!repr.contains("\x1b"));
+    }
 }
\ No newline at end of file
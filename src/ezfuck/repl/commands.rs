@@ -0,0 +1,283 @@
+use std::io::{BufRead, Write};
+use serde_json::{json, Value};
+
+use crate::ezfuck::interpreter::errors::catch_error;
+use crate::ezfuck::interpreter::interpreter::{interpret, ExecutionState};
+use crate::ezfuck::parser::parser::{compile_with_options, count_unclosed_loops, CompileOptions};
+use crate::ezfuck::repl::cell_repr::handle_view_command;
+use crate::program_io::program_io::{FlushPolicy, StreamIo};
+
+// How many previous states are kept for :undo.
+const MAX_UNDO_STATES: usize = 100;
+
+const HELP: &str = "\
+:load <path>            Run a file using the current state
+:reset                  Clear every cell and move the cell pointer back to 0
+:undo                   Revert the effect of the last entry
+:set cell <i> = <value> Set the value of cell i
+:ptr <i>                Move the cell pointer to cell i
+:save <path>            Save the cells and cell pointer to a file
+:restore <path>         Load cells and a cell pointer that were saved with :save
+:view <n>               Only show the n cells around the cell pointer
+:view all               Show every cell
+:pin <i> or <i>-<j>     Always show cell i, or cells i to j
+:unpin                  Stop showing pinned cells
+:format <format>        Show cell values as dec, hex, bin or signed
+:chars <format>         Show cell characters as ascii, escaped or utf8
+:color on|off           Highlight the cell pointer, changed cells and I/O cells
+:names on|off           Show or hide cell names like A and AB
+:help                   Show this message
+!                       Exit the REPL
+";
+
+pub struct UndoStack {
+    states: Vec<ExecutionState>,
+}
+
+impl UndoStack {
+    pub fn new() -> UndoStack {
+        return UndoStack { states: vec![] };
+    }
+
+    pub fn push(self: &mut Self, state: &ExecutionState) -> () {
+        if self.states.len() >= MAX_UNDO_STATES {
+            self.states.remove(0);
+        }
+
+        self.states.push(state.clone());
+    }
+
+    pub fn pop(self: &mut Self) -> Option<ExecutionState> {
+        return self.states.pop();
+    }
+}
+
+// The program reads from program_input when it's given, and shares in_stream with the REPL otherwise. Errors in the code,
+//  or while running it, are reported without ending the session, and leave the state as it was before the code ran.
+pub fn run_code<R: BufRead, W: Write>(code: &str, state: &mut ExecutionState, options: &CompileOptions, in_stream: &mut R, out_stream: &mut W, program_input: Option<&mut (dyn BufRead + '_)>) -> () {
+    let compiled = catch_error(|| count_unclosed_loops(code).map(|n_unclosed| (n_unclosed, compile_with_options(code, options))));
+    let instructions = match compiled {
+        Ok(Some((0, instructions))) => instructions,
+        Err(message) => {
+            writeln!(out_stream, "{message}").unwrap();
+            return;
+        },
+        Ok(Some(_)) => {
+            writeln!(out_stream, "[ missing a matching ]").unwrap();
+            return;
+        },
+        Ok(None) => {
+            writeln!(out_stream, "] missing a matching [").unwrap();
+            return;
+        },
+    };
+
+    let previous_state = state.clone();
+    out_stream.write(b"Output: ").unwrap();
+    let result = catch_error(|| {
+        match program_input {
+            Some(reader) => interpret(&instructions, state, &mut StreamIo::new(reader, &mut *out_stream, FlushPolicy::Byte), false),
+            None => interpret(&instructions, state, &mut StreamIo::new(&mut *in_stream, &mut *out_stream, FlushPolicy::Byte), false),
+        }
+    });
+    state.set_instruction_pointer(0);
+
+    out_stream.write(b"\n").unwrap();
+    if let Err(message) = result {
+        writeln!(out_stream, "{message}").unwrap();
+        *state = previous_state;
+    }
+}
+
+// Display and output settings aren't part of what gets reset or undone.
+fn replace_state(state: &mut ExecutionState, mut new_state: ExecutionState) -> () {
+    new_state.tape_view = state.tape_view.clone();
+    new_state.output_encoding = state.output_encoding;
+    *state = new_state;
+}
+
+fn parse_cell_assignment(argument: &str) -> Option<(usize, u8)> {
+    let assignment = argument.trim().strip_prefix("cell")?;
+    let (raw_i, raw_value) = assignment.split_once('=')?;
+    let cell_i = raw_i.trim().parse::<usize>().ok()?;
+    let value = raw_value.trim().parse::<u8>().ok()?;
+
+    return Some((cell_i, value));
+}
+
+fn save_state(path: &str, state: &ExecutionState) -> Result<(), String> {
+    let saved = json!({ "cells": state.cells, "cell_ptr": state.cell_ptr });
+    return std::fs::write(path, saved.to_string()).map_err(|err| err.to_string());
+}
+
+fn restore_state(path: &str, state: &mut ExecutionState) -> Result<(), String> {
+    let raw = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let saved: Value = serde_json::from_str(&raw).map_err(|err| err.to_string())?;
+
+    let cells: Option<Vec<u8>> = saved["cells"].as_array()
+        .and_then(|cells| cells.iter().map(|cell| cell.as_u64().and_then(|n| u8::try_from(n).ok())).collect());
+    let cell_ptr = saved["cell_ptr"].as_u64().map(|ptr| ptr as usize);
+
+    return match (cells, cell_ptr) {
+        (Some(cells), Some(cell_ptr)) if !cells.is_empty() => {
+            let mut restored_state = ExecutionState::new();
+            restored_state.cells = cells;
+            restored_state.set_cell_pointer(cell_ptr);
+            replace_state(state, restored_state);
+            Ok(())
+        },
+        _ => Err("The file does not contain a saved session".to_string()),
+    }
+}
+
+// Returns true if the entry was a REPL command, and false if it should be run as code.
+pub fn handle_repl_command<R: BufRead, W: Write>(command: &str, state: &mut ExecutionState, undo_stack: &mut UndoStack, options: &CompileOptions, in_stream: &mut R, out_stream: &mut W, program_input: Option<&mut (dyn BufRead + '_)>) -> bool {
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+    let argument = argument.trim();
+
+    if let Some(result) = handle_view_command(name, argument, &mut state.tape_view) {
+        if let Err(message) = result {
+            writeln!(out_stream, "{message}").unwrap();
+        }
+        return true;
+    }
+
+    match name {
+        ":load" => {
+            match std::fs::read_to_string(argument) {
+                Ok(code) => {
+                    undo_stack.push(state);
+                    run_code(&code, state, options, in_stream, out_stream, program_input);
+                },
+                Err(err) => {
+                    writeln!(out_stream, "Could not read file: {err}").unwrap();
+                },
+            }
+        },
+        ":reset" => {
+            undo_stack.push(state);
+            replace_state(state, ExecutionState::new());
+        },
+        ":undo" => {
+            match undo_stack.pop() {
+                Some(previous_state) => replace_state(state, previous_state),
+                None => writeln!(out_stream, "Nothing to undo").unwrap(),
+            }
+        },
+        ":set" => {
+            match parse_cell_assignment(argument) {
+                Some((cell_i, value)) => {
+                    undo_stack.push(state);
+                    let current_cell_ptr = state.cell_ptr;
+                    state.set_cell_pointer(cell_i);
+                    state.set_current_cell(value);
+                    state.cell_ptr = current_cell_ptr;
+                },
+                None => writeln!(out_stream, "Expected :set cell <i> = <value>").unwrap(),
+            }
+        },
+        ":ptr" => {
+            match argument.parse::<usize>() {
+                Ok(cell_ptr) => {
+                    undo_stack.push(state);
+                    state.set_cell_pointer(cell_ptr);
+                },
+                Err(_) => writeln!(out_stream, "Expected :ptr <i>").unwrap(),
+            }
+        },
+        ":save" => {
+            if let Err(err) = save_state(argument, state) {
+                writeln!(out_stream, "Could not save session: {err}").unwrap();
+            }
+        },
+        ":restore" => {
+            let previous_state = state.clone();
+            match restore_state(argument, state) {
+                Ok(()) => undo_stack.push(&previous_state),
+                Err(err) => writeln!(out_stream, "Could not restore session: {err}").unwrap(),
+            }
+        },
+        ":help" => {
+            out_stream.write(HELP.as_bytes()).unwrap();
+        },
+        unknown if unknown.starts_with(":") => {
+            writeln!(out_stream, "Unknown command: {unknown}. Enter :help to see every command.").unwrap();
+        },
+        _ => {
+            return false;
+        }
+    }
+
+    return true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_command(command: &str, state: &mut ExecutionState, undo_stack: &mut UndoStack) -> String {
+        let mut input = &b""[..];
+        let mut output = vec![];
+        assert!(handle_repl_command(command, state, undo_stack, &CompileOptions::new(false), &mut input, &mut output, None));
+
+        return String::from_utf8(output).unwrap();
+    }
+
+    #[test]
+    fn it_should_set_a_cell_without_moving_the_pointer() {
+        let mut state = ExecutionState::new();
+        let mut undo_stack = UndoStack::new();
+        run_command(":set cell 3 = 72", &mut state, &mut undo_stack);
+
+        assert_eq!(state.cells, vec![0, 0, 0, 72]);
+        assert_eq!(state.cell_ptr, 0);
+    }
+
+    #[test]
+    fn it_should_undo_the_last_change() {
+        let mut state = ExecutionState::new();
+        let mut undo_stack = UndoStack::new();
+        run_command(":ptr 2", &mut state, &mut undo_stack);
+        run_command(":set cell 0 = 5", &mut state, &mut undo_stack);
+
+        run_command(":undo", &mut state, &mut undo_stack);
+        assert_eq!(state.cells, vec![0, 0, 0]);
+        assert_eq!(state.cell_ptr, 2);
+
+        run_command(":undo", &mut state, &mut undo_stack);
+        assert_eq!(state.cell_ptr, 0);
+
+        let output = run_command(":undo", &mut state, &mut undo_stack);
+        assert_eq!(output, "Nothing to undo\n");
+    }
+
+    #[test]
+    fn it_should_restore_a_saved_session() {
+        let path = std::env::temp_dir().join("ezfuck_repl_command_test_session.json");
+        let path = path.to_str().unwrap();
+
+        let mut state = ExecutionState::new();
+        let mut undo_stack = UndoStack::new();
+        run_command(":set cell 1 = 9", &mut state, &mut undo_stack);
+        run_command(":ptr 1", &mut state, &mut undo_stack);
+        run_command(&format!(":save {path}"), &mut state, &mut undo_stack);
+
+        run_command(":reset", &mut state, &mut undo_stack);
+        assert_eq!(state.cells, vec![0]);
+
+        run_command(&format!(":restore {path}"), &mut state, &mut undo_stack);
+        assert_eq!(state.cells, vec![0, 9]);
+        assert_eq!(state.cell_ptr, 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_should_not_treat_code_as_a_command() {
+        let mut state = ExecutionState::new();
+        let mut input = &b""[..];
+        let mut output = vec![];
+
+        assert!(!handle_repl_command("+5", &mut state, &mut UndoStack::new(), &CompileOptions::new(false), &mut input, &mut output, None));
+    }
+}
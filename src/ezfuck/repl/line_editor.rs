@@ -0,0 +1,104 @@
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use crate::ezfuck::interpreter::interrupt::clear_interrupt;
+
+pub trait LineEditor<R: BufRead, W: Write> {
+    // Returns None once there is nothing left to read.
+    fn read_line(self: &mut Self, prompt: &str, in_stream: &mut R, out_stream: &mut W) -> Option<String>;
+
+    fn add_history_entry(self: &mut Self, entry: &str) -> ();
+}
+
+// Reads plain lines from the REPL's input stream. Used when input isn't coming from a terminal.
+pub struct StreamLineEditor;
+
+impl<R: BufRead, W: Write> LineEditor<R, W> for StreamLineEditor {
+    fn read_line(self: &mut Self, prompt: &str, in_stream: &mut R, out_stream: &mut W) -> Option<String> {
+        out_stream.write(prompt.as_bytes()).unwrap();
+        out_stream.flush().unwrap();
+
+        let mut input_buffer: String = String::new();
+        let read_result = in_stream.read_line(&mut input_buffer);
+        clear_interrupt();
+
+        return match read_result {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(input_buffer.trim_end_matches(['\r', '\n']).to_string()),
+        }
+    }
+
+    fn add_history_entry(self: &mut Self, _entry: &str) -> () {}
+}
+
+// Reads from the terminal with arrow-key editing, and keeps history between sessions.
+pub struct TerminalLineEditor {
+    editor: DefaultEditor,
+    history_path: Option<PathBuf>,
+    was_interrupted: bool,
+}
+
+impl TerminalLineEditor {
+    pub fn new(history_path: Option<PathBuf>) -> TerminalLineEditor {
+        let mut editor = DefaultEditor::new().expect("Creating line editor");
+        if let Some(path) = &history_path {
+            // The history file won't exist the first time the REPL is used.
+            let _ = editor.load_history(path);
+        }
+
+        return TerminalLineEditor { editor, history_path, was_interrupted: false };
+    }
+}
+
+impl Drop for TerminalLineEditor {
+    fn drop(self: &mut Self) -> () {
+        if let Some(path) = &self.history_path {
+            if let Err(err) = self.editor.save_history(path) {
+                eprintln!("Could not save REPL history: {err}");
+            }
+        }
+    }
+}
+
+impl<R: BufRead, W: Write> LineEditor<R, W> for TerminalLineEditor {
+    fn read_line(self: &mut Self, prompt: &str, _in_stream: &mut R, out_stream: &mut W) -> Option<String> {
+        out_stream.flush().unwrap();
+
+        loop {
+            let read_result = self.editor.readline(prompt);
+            clear_interrupt();
+
+            return match read_result {
+                Ok(line) => {
+                    self.was_interrupted = false;
+                    Some(line)
+                },
+                Err(ReadlineError::Interrupted) => {
+                    // Ctrl+C twice in a row at the prompt exits.
+                    if self.was_interrupted {
+                        None
+                    } else {
+                        self.was_interrupted = true;
+                        writeln!(out_stream, "(To exit, press Ctrl+C again or enter !)").unwrap();
+                        continue;
+                    }
+                },
+                Err(ReadlineError::Eof) => None,
+                Err(err) => {
+                    eprintln!("Could not read line: {err}");
+                    None
+                },
+            }
+        }
+    }
+
+    fn add_history_entry(self: &mut Self, entry: &str) -> () {
+        let _ = self.editor.add_history_entry(entry);
+    }
+}
+
+pub fn default_history_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    return Some(PathBuf::from(home).join(".ezfuck_history"));
+}
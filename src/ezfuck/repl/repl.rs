@@ -1,35 +1,114 @@
 use std::cmp::max;
 use std::io::{BufRead, Read, Write};
 
-use crate::ezfuck::interpreter::interpreter::{interpret, ExecutionState};
-use crate::ezfuck::parser::parser::{compile_to_intermediate};
-use crate::ezfuck::repl::cell_repr::{produce_cells_repr};
+use crate::ezfuck::interpreter::errors::catch_error;
+use crate::ezfuck::interpreter::interpreter::ExecutionState;
+use crate::ezfuck::parser::parser::{count_unclosed_loops, CompileOptions};
+use crate::ezfuck::repl::cell_repr::{produce_state_cells_repr, TapeView};
+use crate::ezfuck::repl::commands::{handle_repl_command, run_code, UndoStack};
+use crate::ezfuck::repl::line_editor::LineEditor;
+use crate::program_io::program_io::OutputEncoding;
 
-pub fn start_repl<R: BufRead, W: Write>(in_stream: &mut R, out_stream: &mut W) {
+// Keeps reading lines while there are unclosed loops, so that loops and macros can be typed across multiple lines. An
+//  entry with an error in it is finished, so that the error can be reported when it's run.
+fn read_entry<R: BufRead, W: Write, E: LineEditor<R, W>>(editor: &mut E, in_stream: &mut R, out_stream: &mut W) -> Option<String> {
+    let mut entry = editor.read_line("EZ> ", in_stream, out_stream)?;
+    while catch_error(|| count_unclosed_loops(&entry)).ok().flatten().unwrap_or(0) > 0 {
+        let line = editor.read_line("..> ", in_stream, out_stream)?;
+        entry.push('\n');
+        entry.push_str(&line);
+    }
+
+    return Some(entry);
+}
+
+pub fn start_repl<R: BufRead, W: Write, E: LineEditor<R, W>>(editor: &mut E, in_stream: &mut R, out_stream: &mut W, tape_view: TapeView, output_encoding: OutputEncoding, options: &CompileOptions, mut program_input: Option<Box<dyn BufRead>>) {
     let mut state = ExecutionState::new();
+    state.tape_view = tape_view;
+    state.output_encoding = output_encoding;
+    let mut undo_stack = UndoStack::new();
+    // Breakpoints aren't supported in the REPL.
+    let options = CompileOptions { allow_debugging: false, ..options.clone() };
 
     loop {
-        let cells_repr = produce_cells_repr(&state.cells, state.cell_ptr);
+        let cells_repr = produce_state_cells_repr(&mut state);
         out_stream.write(cells_repr.as_bytes()).unwrap();
         out_stream.flush().unwrap();
 
-        out_stream.write(b"EZ> ").unwrap();
-        out_stream.flush().unwrap();
+        let input_buffer = match read_entry(editor, in_stream, out_stream) {
+            Some(entry) => entry,
+            None => break,
+        };
 
-        let mut input_buffer: String = String::new();
-        in_stream.read_line(&mut input_buffer).unwrap();
+        if !input_buffer.trim().is_empty() {
+            editor.add_history_entry(&input_buffer);
+        }
 
         if input_buffer.starts_with("!") {
             break;
-        } else {
-            let instructions = compile_to_intermediate(&input_buffer, false);
-
-            out_stream.write(b"Output: ").unwrap();
-            interpret(&instructions, &mut state, in_stream, out_stream, false);
-            state.set_instruction_pointer(0);
-
-            out_stream.write(b"\n").unwrap();
+        } else if !handle_repl_command(input_buffer.trim(), &mut state, &mut undo_stack, &options, in_stream, out_stream, program_input.as_deref_mut()) {
+            undo_stack.push(&state);
+            run_code(&input_buffer, &mut state, &options, in_stream, out_stream, program_input.as_deref_mut());
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::ezfuck::repl::line_editor::StreamLineEditor;
+    use super::*;
+
+    fn run_repl(lines: &str) -> String {
+        let mut input = lines.as_bytes();
+        let mut output = vec![];
+        start_repl(&mut StreamLineEditor, &mut input, &mut output, TapeView::new(), OutputEncoding::Raw, &CompileOptions::new(false), None);
+
+        return String::from_utf8(output).unwrap();
+    }
+
+    #[test]
+    fn it_should_continue_reading_lines_until_loops_are_closed() {
+        let output = run_repl("+8[>+8\n<-]>+.\n!\n");
+
+        assert!(output.contains("..> "));
+        assert!(output.contains("Output: A"));
+    }
+
+    #[test]
+    fn it_should_continue_reading_lines_until_macros_are_closed() {
+        let output = run_repl("def a {\n^65 .\n} a a\n!\n");
+
+        assert!(output.contains("..> "));
+        assert!(output.contains("Output: AA"));
+    }
+
+    #[test]
+    fn it_should_report_unmatched_loop_ends_instead_of_panicking() {
+        let output = run_repl("+]\n!\n");
+
+        assert!(output.contains("] missing a matching ["));
+    }
+
+    #[test]
+    fn it_should_report_errors_and_keep_the_previous_state() {
+        let output = run_repl("+5>+2\n+300\n+<<\n+.\n!\n");
+
+        assert!(output.contains("300 at line 1, column 1 is too large for a cell"));
+        assert!(output.contains("Output: \nCell Pointer Became Negative!\n"));
+        assert!(output.contains("Output: \u{3}\n"));
+    }
+
+    #[test]
+    fn it_should_undo_the_last_line() {
+        let output = run_repl("+65\n+1\n:undo\n.\n!\n");
+
+        assert!(output.ends_with("Output: A\n     V  \ni | 000 |\nn |  A  |\nd | 065 |\na |  A  |\nEZ> "));
+    }
+
+    #[test]
+    fn it_should_end_the_session_once_input_runs_out() {
+        let output = run_repl("+");
+
+        assert!(output.ends_with("EZ> "));
+    }
+}
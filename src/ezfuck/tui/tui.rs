@@ -0,0 +1,498 @@
+use std::cmp::{max, min};
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::ezfuck::interpreter::debugger::step_back_to_breakpoint;
+use crate::ezfuck::interpreter::errors::catch_error;
+use crate::ezfuck::interpreter::interpreter::{interpret_instruction, ExecutionState};
+use crate::ezfuck::parser::parser::{compile_with_source_map, CompileOptions, Instruction, SourceMap, SourcePosition};
+use crate::ezfuck::repl::cell_repr::produce_cells_repr;
+use crate::program_io::program_io::MemoryIo;
+
+// How many instructions are executed between redraws while continuing.
+const INSTRUCTIONS_PER_FRAME: usize = 10_000;
+
+const KEYS_HELP: &str = "s step  b back  c continue  r reverse  i input  q quit";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Mode {
+    Paused,
+    Running,
+    // The text typed so far, which is given to the program as input once Enter is pressed.
+    EnteringInput(String),
+    Finished,
+}
+
+struct TuiSession {
+    // The lines of each source, indexed like the sources in the source map.
+    source_lines: Vec<Vec<String>>,
+    instructions: Vec<Instruction>,
+    source_map: SourceMap,
+    state: ExecutionState,
+    // Everything that's been given to the program as input, including what it's already read.
+    input: Vec<u8>,
+    output: Vec<u8>,
+    mode: Mode,
+    message: String,
+}
+
+impl TuiSession {
+    fn new(code: &str, path: Option<&Path>, options: &CompileOptions, mut state: ExecutionState) -> TuiSession {
+        let (instructions, source_map) = compile_with_source_map(code, path, options);
+        state.start_recording();
+
+        let mode = if instructions.is_empty() { Mode::Finished } else { Mode::Paused };
+
+        return TuiSession {
+            source_lines: source_map.sources.iter().map(|source| source.lines()).collect(),
+            instructions,
+            source_map,
+            state,
+            input: vec![],
+            output: vec![],
+            mode,
+            message: String::new(),
+        };
+    }
+
+    fn is_finished(self: &Self) -> bool {
+        return self.state.instruction_ptr >= self.instructions.len();
+    }
+
+    fn finish(self: &mut Self) -> () {
+        self.mode = Mode::Finished;
+        self.message = "The program finished".to_string();
+    }
+
+    // The program stays where it failed so that it can still be inspected and stepped back from.
+    fn fail(self: &mut Self, message: &str) -> () {
+        self.mode = Mode::Finished;
+        self.message = format!("The program failed: {message}");
+    }
+
+    // Returns false if the instruction couldn't be executed, either because the program is waiting for input or
+    //  because it failed.
+    fn execute_current_instruction(self: &mut Self) -> bool {
+        let instruction = self.instructions[self.state.instruction_ptr];
+
+        // Input that's been read before is replayed from the state's input history, so only unread input is offered.
+        let read_len = max(self.state.recorded_input_end(), self.state.input_position);
+        let unread_input = &self.input[min(read_len, self.input.len())..];
+        let is_replaying = self.state.input_position < self.state.recorded_input_end();
+        if instruction.reads_input() && !is_replaying && unread_input.is_empty() {
+            self.wait_for_input();
+            return false;
+        }
+
+        // Output that was written before stepping backwards is discarded so that it isn't written twice.
+        self.output.truncate(self.state.output_position);
+
+        let mut io = MemoryIo::new(unread_input);
+        self.state.record_snapshot();
+        let result = catch_error(|| interpret_instruction(instruction, &mut self.state, &mut io, true));
+        self.output.extend(io.output);
+        if let Err(message) = result {
+            self.fail(&message);
+            return false;
+        }
+
+        self.state.instruction_ptr += 1;
+
+        return true;
+    }
+
+    fn wait_for_input(self: &mut Self) -> () {
+        self.mode = Mode::EnteringInput(String::new());
+        self.message = "The program is waiting for input".to_string();
+    }
+
+    fn step(self: &mut Self) -> () {
+        if self.is_finished() {
+            self.finish();
+        } else if self.execute_current_instruction() {
+            if self.is_finished() {
+                self.finish();
+            } else {
+                self.state.is_debugging = false;
+                self.mode = Mode::Paused;
+            }
+        }
+    }
+
+    fn step_back(self: &mut Self) -> () {
+        if self.state.step_back() {
+            self.mode = Mode::Paused;
+            self.message.clear();
+        } else {
+            self.message = "Reached the start of the recorded history".to_string();
+        }
+    }
+
+    fn reverse_continue(self: &mut Self) -> () {
+        let n_stepped = step_back_to_breakpoint(&self.instructions, &mut self.state);
+        self.mode = Mode::Paused;
+        self.message = format!("Stepped back {n_stepped} instruction(s)");
+    }
+
+    fn continue_execution(self: &mut Self) -> () {
+        self.mode = Mode::Running;
+        self.message.clear();
+    }
+
+    fn pause(self: &mut Self) -> () {
+        self.mode = Mode::Paused;
+        self.message = "Paused".to_string();
+    }
+
+    // Runs a bounded number of instructions so that the UI can keep responding while a long program runs.
+    fn run_slice(self: &mut Self) -> () {
+        for _ in 0..INSTRUCTIONS_PER_FRAME {
+            if self.mode != Mode::Running {
+                return;
+            }
+
+            if self.is_finished() {
+                self.finish();
+                return;
+            }
+
+            if !self.execute_current_instruction() {
+                return;
+            }
+
+            if self.state.is_debugging {
+                // A "!" in the source acts as a breakpoint.
+                self.state.is_debugging = false;
+                self.mode = Mode::Paused;
+                self.message = "Stopped at a breakpoint".to_string();
+                return;
+            }
+        }
+    }
+
+    fn submit_input(self: &mut Self, line: &str) -> () {
+        self.input.extend_from_slice(line.as_bytes());
+        self.input.push(b'\n');
+        self.mode = if self.is_finished() { Mode::Finished } else { Mode::Paused };
+        self.message.clear();
+    }
+
+    // Returns false once the debugger should exit.
+    fn handle_key(self: &mut Self, key: KeyEvent) -> bool {
+        if let Mode::EnteringInput(typed) = &mut self.mode {
+            match key.code {
+                KeyCode::Enter => {
+                    let line = typed.clone();
+                    self.submit_input(&line);
+                },
+                KeyCode::Esc => {
+                    self.mode = if self.is_finished() { Mode::Finished } else { Mode::Paused };
+                    self.message.clear();
+                },
+                KeyCode::Backspace => {
+                    typed.pop();
+                },
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return false;
+                },
+                KeyCode::Char(c) => {
+                    typed.push(c);
+                },
+                _ => (),
+            }
+            return true;
+        }
+
+        if self.mode == Mode::Running {
+            // Any key pauses a running program, so that it's always possible to get control back.
+            self.pause();
+            return true;
+        }
+
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return false;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('s') | KeyCode::Char('n') | KeyCode::Right | KeyCode::Char(' ') => self.step(),
+            KeyCode::Char('b') | KeyCode::Left => self.step_back(),
+            KeyCode::Char('c') => {
+                if self.is_finished() {
+                    self.finish();
+                } else {
+                    self.continue_execution();
+                }
+            },
+            KeyCode::Char('r') => self.reverse_continue(),
+            KeyCode::Char('i') => {
+                self.mode = Mode::EnteringInput(String::new());
+                self.message = "Type input for the program, then press Enter".to_string();
+            },
+            _ => (),
+        }
+
+        return true;
+    }
+
+    // Output written after the current point in the program's history isn't shown after stepping backwards.
+    fn visible_output(self: &Self) -> &[u8] {
+        return &self.output[..min(self.state.output_position, self.output.len())];
+    }
+
+    fn current_source_position(self: &Self) -> Option<SourcePosition> {
+        return self.source_map.positions.get(self.state.instruction_ptr).copied();
+    }
+
+    // The source that the current instruction is in, which may be an included file.
+    fn current_file(self: &Self) -> usize {
+        return self.current_source_position().map_or(0, |position| position.file);
+    }
+}
+
+// Keeps the focused line roughly in the middle of a pane that's `height` lines tall.
+fn find_scroll_offset(focused_line_i: usize, height: u16) -> u16 {
+    return focused_line_i.saturating_sub(height as usize / 2) as u16;
+}
+
+fn produce_source_text(session: &TuiSession) -> Text<'static> {
+    let position = session.current_source_position();
+
+    let lines: Vec<Line> = session.source_lines[session.current_file()].iter().enumerate().map(|(line_i, line)| {
+        match position {
+            Some(SourcePosition { line: current_line, column, .. }) if current_line == line_i + 1 => {
+                let chars: Vec<char> = line.chars().collect();
+                let token_i = min(column - 1, chars.len());
+                let before: String = chars[..token_i].iter().collect();
+                let token: String = chars.get(token_i).map(|c| c.to_string()).unwrap_or(" ".to_string());
+                let after: String = chars.get(token_i + 1..).map(|rest| rest.iter().collect()).unwrap_or_default();
+                Line::from(vec![Span::raw(before), Span::styled(token, Style::new().reversed().bold()), Span::raw(after)])
+            },
+            _ => Line::raw(line.clone()),
+        }
+    }).collect();
+
+    return Text::from(lines);
+}
+
+fn produce_instructions_text(session: &TuiSession) -> Text<'static> {
+    let places = session.instructions.len().to_string().len();
+
+    let lines: Vec<Line> = session.instructions.iter().enumerate().map(|(i, instruction)| {
+        let text = format!("{i:0places$} {:?}", instruction);
+        if i == session.state.instruction_ptr {
+            Line::styled(text, Style::new().reversed())
+        } else {
+            Line::raw(text)
+        }
+    }).collect();
+
+    return Text::from(lines);
+}
+
+fn produce_input_text(session: &TuiSession) -> Text<'static> {
+    let read_len = session.state.input_position.min(session.input.len());
+    let read: String = session.input[..read_len].iter().map(|byte| char::from(*byte)).collect();
+    let unread: String = session.input[read_len..].iter().map(|byte| char::from(*byte)).collect();
+
+    let mut text = Text::from(Line::from(vec![Span::styled(read, Style::new().dim()), Span::raw(unread)]));
+    if let Mode::EnteringInput(typed) = &session.mode {
+        text.push_line(Line::from(vec![Span::styled("> ", Style::new().bold()), Span::raw(typed.clone())]));
+    }
+
+    return text;
+}
+
+fn render(frame: &mut Frame, session: &TuiSession) -> () {
+    let area = frame.area();
+
+    // The tape is rendered first so that its pane can be sized to fit it.
+    let mut tape_view = session.state.tape_view.clone();
+    tape_view.use_color = false;
+    tape_view.wrap_width = Some(area.width.saturating_sub(2) as usize);
+    let tape_repr = produce_cells_repr(&session.state.cells, session.state.cell_ptr, &tape_view, &HashSet::new());
+    let tape_height = tape_repr.lines().count() as u16 + 2;
+
+    let [code_area, tape_area, io_area, status_area] = Layout::vertical([
+        Constraint::Min(5),
+        Constraint::Length(tape_height),
+        Constraint::Length(8),
+        Constraint::Length(1),
+    ]).areas(area);
+    let [source_area, instructions_area] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(code_area);
+    let [output_area, input_area] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(io_area);
+
+    render_source(frame, session, source_area);
+    render_instructions(frame, session, instructions_area);
+
+    frame.render_widget(Paragraph::new(tape_repr).block(Block::bordered().title("Tape")), tape_area);
+
+    let output = String::from_utf8_lossy(session.visible_output()).to_string();
+    let output_lines = output.lines().count() as u16;
+    let output_scroll = output_lines.saturating_sub(output_area.height.saturating_sub(2));
+    let output_paragraph = Paragraph::new(output).block(Block::bordered().title("Output")).wrap(Wrap { trim: false }).scroll((output_scroll, 0));
+    frame.render_widget(output_paragraph, output_area);
+
+    let input_paragraph = Paragraph::new(produce_input_text(session)).block(Block::bordered().title("Input")).wrap(Wrap { trim: false });
+    frame.render_widget(input_paragraph, input_area);
+
+    let mode_label = match session.mode {
+        Mode::Paused => "PAUSED",
+        Mode::Running => "RUNNING",
+        Mode::EnteringInput(_) => "INPUT",
+        Mode::Finished => "FINISHED",
+    };
+    let status = Line::from(vec![
+        Span::styled(format!(" {mode_label} "), Style::new().reversed()),
+        Span::raw(format!(" {KEYS_HELP}  {}", session.message)),
+    ]);
+    frame.render_widget(Paragraph::new(status), status_area);
+}
+
+fn render_source(frame: &mut Frame, session: &TuiSession, area: Rect) -> () {
+    let focused_line_i = session.current_source_position().map_or(0, |position| position.line - 1);
+    let scroll = find_scroll_offset(focused_line_i, area.height.saturating_sub(2));
+
+    let title = match &session.source_map.sources[session.current_file()].path {
+        Some(path) => format!("Source: {}", path.display()),
+        None => "Source".to_string(),
+    };
+
+    let paragraph = Paragraph::new(produce_source_text(session)).block(Block::bordered().title(title)).scroll((scroll, 0));
+    frame.render_widget(paragraph, area);
+}
+
+fn render_instructions(frame: &mut Frame, session: &TuiSession, area: Rect) -> () {
+    let scroll = find_scroll_offset(session.state.instruction_ptr, area.height.saturating_sub(2));
+
+    let paragraph = Paragraph::new(produce_instructions_text(session)).block(Block::bordered().title("Instructions")).scroll((scroll, 0));
+    frame.render_widget(paragraph, area);
+}
+
+fn run_session(terminal: &mut DefaultTerminal, session: &mut TuiSession) -> io::Result<()> {
+    loop {
+        terminal.draw(|frame| render(frame, session))?;
+
+        // While running, the program keeps executing between checks for key presses instead of waiting for one.
+        let timeout = if session.mode == Mode::Running { Duration::ZERO } else { Duration::from_millis(250) };
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !session.handle_key(key) {
+                    return Ok(());
+                }
+            }
+        }
+
+        session.run_slice();
+    }
+}
+
+// Input that's given upfront is offered to the program before anything typed into the input prompt.
+pub fn start_tui_debugger(code: &str, path: Option<&Path>, options: &CompileOptions, state: ExecutionState, input: Vec<u8>) -> () {
+    // Compiling happens before the terminal is taken over, so that compile errors are printed normally.
+    let mut session = TuiSession::new(code, path, options, state);
+    session.input = input;
+
+    let mut terminal = ratatui::init();
+    let result = run_session(&mut terminal, &mut session);
+    ratatui::restore();
+
+    if let Err(err) = result {
+        eprintln!("Could not run the debugger: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use super::*;
+
+    fn press(session: &mut TuiSession, code: KeyCode) -> bool {
+        return session.handle_key(KeyEvent::new(code, KeyModifiers::NONE));
+    }
+
+    fn continue_until_stopped(session: &mut TuiSession) -> () {
+        press(session, KeyCode::Char('c'));
+        while session.mode == Mode::Running {
+            session.run_slice();
+        }
+    }
+
+    #[test]
+    fn it_should_step_forwards_and_backwards() {
+        let mut session = TuiSession::new("+5.>+", None, &CompileOptions::new(true), ExecutionState::with_history_size(100));
+        press(&mut session, KeyCode::Char('s'));
+        press(&mut session, KeyCode::Char('s'));
+        assert_eq!(session.state.cells, vec![5]);
+        assert_eq!(session.output, vec![5]);
+
+        press(&mut session, KeyCode::Char('b'));
+        assert_eq!(session.state.instruction_ptr, 1);
+        assert!(session.visible_output().is_empty());
+
+        press(&mut session, KeyCode::Char('s'));
+        assert_eq!(session.output, vec![5]);
+    }
+
+    #[test]
+    fn it_should_continue_until_a_breakpoint() {
+        let mut session = TuiSession::new("+++!++", None, &CompileOptions::new(true), ExecutionState::with_history_size(100));
+        continue_until_stopped(&mut session);
+
+        assert_eq!(session.mode, Mode::Paused);
+        assert_eq!(session.state.get_current_cell(), 3);
+
+        continue_until_stopped(&mut session);
+        assert_eq!(session.mode, Mode::Finished);
+        assert_eq!(session.state.get_current_cell(), 5);
+    }
+
+    #[test]
+    fn it_should_wait_for_input_when_the_program_reads() {
+        let mut session = TuiSession::new(",.", None, &CompileOptions::new(true), ExecutionState::with_history_size(100));
+        continue_until_stopped(&mut session);
+        assert_eq!(session.mode, Mode::EnteringInput(String::new()));
+
+        press(&mut session, KeyCode::Char('A'));
+        press(&mut session, KeyCode::Enter);
+        continue_until_stopped(&mut session);
+
+        assert_eq!(session.mode, Mode::Finished);
+        assert_eq!(session.output, vec![b'A']);
+    }
+
+    #[test]
+    fn it_should_stop_and_show_the_error_when_the_program_fails() {
+        let mut session = TuiSession::new("+5. > < %@>1 +", None, &CompileOptions::new(true), ExecutionState::with_history_size(100));
+        continue_until_stopped(&mut session);
+
+        assert_eq!(session.mode, Mode::Finished);
+        assert!(session.message.contains("Divided by zero"));
+        assert_eq!(session.state.instruction_ptr, 4);
+        assert_eq!(session.output, vec![5]);
+    }
+
+    #[test]
+    fn it_should_show_the_source_instructions_and_tape() {
+        let mut session = TuiSession::new("+5\n>.", None, &CompileOptions::new(true), ExecutionState::with_history_size(100));
+        press(&mut session, KeyCode::Char('s'));
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|frame| render(frame, &session)).unwrap();
+
+        let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains("Source"));
+        assert!(screen.contains("AddToCellPtr"));
+        assert!(screen.contains("| 005 |"));
+        assert!(screen.contains("PAUSED"));
+    }
+}
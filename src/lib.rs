@@ -0,0 +1,3 @@
+pub mod standard_brainfuck;
+pub mod ezfuck;
+pub mod program_io;
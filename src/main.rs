@@ -1,30 +1,172 @@
+use std::fs::File;
 use std::io;
-use clap::Parser;
-use crate::ezfuck::repl::repl::start_repl;
-
-mod standard_brainfuck;
-mod ezfuck;
+use std::io::{BufRead, BufReader, Cursor, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use clap::{Parser, Subcommand, ValueEnum};
+use ezfuck::ezfuck::dap::dap::start_dap_server;
+use ezfuck::ezfuck::interpreter::history::DEFAULT_HISTORY_SIZE;
+use ezfuck::ezfuck::interpreter::interpreter::{interpret_with_std_io, ExecutionState};
+use ezfuck::ezfuck::interpreter::interrupt::install_interrupt_handler;
+use ezfuck::ezfuck::parser::parser::{compile_with_source_map, CompileOptions};
+use ezfuck::ezfuck::repl::cell_repr::{should_use_color, TapeView};
+use ezfuck::ezfuck::repl::line_editor::{default_history_path, StreamLineEditor, TerminalLineEditor};
+use ezfuck::ezfuck::repl::repl::start_repl;
+use ezfuck::ezfuck::tui::tui::start_tui_debugger;
+use ezfuck::program_io::program_io::{FlushPolicy, OutputEncoding};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long)]
     path: Option<String>,
+
+    /// How many executed instructions the debugger remembers for stepping backwards. 0 disables recording.
+    #[arg(long, default_value_t = DEFAULT_HISTORY_SIZE)]
+    history_size: usize,
+
+    /// Whether to highlight the cell pointer, changed cells and I/O cells with colors
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Treat any character that isn't a command, a comment or whitespace as an error
+    #[arg(long)]
+    strict: bool,
+
+    /// A directory to search for included files in, after the directory of the including file. Can be given more than once
+    #[arg(long = "include-path")]
+    include_paths: Vec<PathBuf>,
+
+    /// Enable the numeric I/O extension: "=" prints the current cell as a decimal number, and ";" reads one into it
+    #[arg(long)]
+    numeric_io: bool,
+
+    /// When the program's output is written: after every byte, every line, before reading input, or only on exit.
+    /// Defaults to every byte when stdout is a terminal, and on exit otherwise
+    #[arg(long, value_enum)]
+    flush: Option<FlushPolicy>,
+
+    /// How "." writes a cell: as a raw byte, or as the Unicode character with the cell's value as its code point, in UTF-8. latin1 is an alias of raw
+    #[arg(long, value_enum, default_value_t = OutputEncoding::Raw)]
+    output_encoding: OutputEncoding,
+
+    /// A file the program reads its input from, instead of stdin
+    #[arg(long, conflicts_with = "input_string")]
+    input: Option<PathBuf>,
+
+    /// A string the program reads as its input, instead of stdin
+    #[arg(long)]
+    input_string: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Start a Debug Adapter Protocol server that communicates over stdin/stdout
+    Dap,
+    /// Run a file in the debugger, paused before its first instruction
+    Debug {
+        path: String,
+
+        /// Use a full-screen terminal UI instead of the text debugger
+        #[arg(long)]
+        tui: bool,
+    },
+}
+
+// Returns None when the program should read from stdin.
+fn open_program_input(args: &Args) -> Result<Option<Box<dyn BufRead>>, String> {
+    if let Some(path) = &args.input {
+        let file = File::open(path).map_err(|err| format!("Could not read input file: {err}"))?;
+        return Ok(Some(Box::new(BufReader::new(file))));
+    }
+
+    return Ok(args.input_string.as_ref().map(|string| Box::new(Cursor::new(string.clone().into_bytes())) as Box<dyn BufRead>));
 }
 
-fn interpret_string(code: &str, allow_debugging: bool) -> () {
-    let instructions = ezfuck::parser::parser::compile_to_intermediate(code, allow_debugging);
-    ezfuck::interpreter::interpreter::interpret_with_std_io(&instructions, allow_debugging);
+fn interpret_string(code: &str, path: &Path, options: &CompileOptions, mut state: ExecutionState, flush_policy: FlushPolicy, program_input: Option<Box<dyn BufRead>>) -> () {
+    let (instructions, _) = compile_with_source_map(code, Some(path), options);
+    interpret_with_std_io(&instructions, &mut state, program_input, flush_policy, options.allow_debugging);
+}
+
+fn debug_file(path: &str, use_tui: bool, options: &CompileOptions, mut state: ExecutionState, flush_policy: FlushPolicy, program_input: Option<Box<dyn BufRead>>) -> () {
+    let code = match std::fs::read_to_string(path) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Could not read file: {err}");
+            return;
+        }
+    };
+
+    if use_tui {
+        // The TUI takes over the terminal, so the program's input has to be given to it upfront.
+        let mut input = vec![];
+        if let Some(mut reader) = program_input {
+            if let Err(err) = reader.read_to_end(&mut input) {
+                eprintln!("Could not read input: {err}");
+                return;
+            }
+        }
+
+        start_tui_debugger(&code, Some(Path::new(path)), options, state, input);
+    } else {
+        let (instructions, _) = compile_with_source_map(&code, Some(Path::new(path)), options);
+        state.is_debugging = true;
+
+        interpret_with_std_io(&instructions, &mut state, program_input, flush_policy, true);
+    }
 }
 
 fn main() {
     let args = Args::parse();
 
+    if let Some(Command::Dap) = args.command {
+        start_dap_server();
+        return;
+    }
+
+    install_interrupt_handler();
+
+    let mut tape_view = TapeView::new();
+    tape_view.use_color = match args.color {
+        ColorChoice::Auto => should_use_color(),
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+
+    let compile_options = CompileOptions { allow_debugging: true, is_strict: args.strict, include_paths: args.include_paths.clone(), allow_numeric_io: args.numeric_io };
+    let flush_policy = args.flush.unwrap_or_else(FlushPolicy::for_stdout);
+    let program_input = match open_program_input(&args) {
+        Ok(program_input) => program_input,
+        Err(message) => {
+            eprintln!("{message}");
+            return;
+        }
+    };
+
+    let mut state = ExecutionState::with_history_size(args.history_size);
+    state.tape_view = tape_view.clone();
+    state.output_encoding = args.output_encoding;
+    state.evaluation_options = CompileOptions { allow_debugging: false, ..compile_options.clone() };
+
+    if let Some(Command::Debug { path, tui }) = &args.command {
+        debug_file(path, *tui, &compile_options, state, flush_policy, program_input);
+        return;
+    }
+
     match args.path {
         Some(path) => {
-            match std::fs::read_to_string(path) {
+            match std::fs::read_to_string(&path) {
                 Ok(code) => {
-                    interpret_string(code.as_str(), true);
+                    interpret_string(code.as_str(), Path::new(&path), &compile_options, state, flush_policy, program_input);
                 }
                 Err(err) => {
                     eprintln!("Could not read file: {err}");
@@ -37,7 +179,12 @@ fn main() {
 
             let mut stdout = io::stdout();
 
-            start_repl(&mut input, &mut stdout);
+            if stdin.is_terminal() {
+                let mut editor = TerminalLineEditor::new(default_history_path());
+                start_repl(&mut editor, &mut input, &mut stdout, tape_view, args.output_encoding, &compile_options, program_input);
+            } else {
+                start_repl(&mut StreamLineEditor, &mut input, &mut stdout, tape_view, args.output_encoding, &compile_options, program_input);
+            }
         }
     }
 }
@@ -0,0 +1 @@
+pub mod program_io;
\ No newline at end of file
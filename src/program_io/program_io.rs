@@ -0,0 +1,359 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Stdout, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use clap::ValueEnum;
+
+// How much output is buffered for stdout when it isn't flushed after every byte.
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+static STDOUT_BUFFER: OnceLock<Mutex<BufWriter<Stdout>>> = OnceLock::new();
+
+// When buffered output is actually written. Every policy but Exit also flushes before reading input, so that a prompt is
+//  shown before the program waits for an answer to it.
+#[derive(ValueEnum, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FlushPolicy {
+    Byte,
+    Line,
+    Input,
+    Exit,
+}
+
+// How a printed cell is turned into output. Raw writes the cell as a single byte, so programs can produce binary data.
+//  Latin1 is an alias of Raw, since every cell value is a Latin-1 character encoded as that same byte. Utf8Codepoints
+//  writes the cell as the Unicode character it's the code point of, in UTF-8.
+#[derive(ValueEnum, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutputEncoding {
+    Raw,
+    Latin1,
+    Utf8Codepoints,
+}
+
+impl FlushPolicy {
+    // Output shows up as soon as it's written when someone is watching it, and is buffered when it's going to a file or
+    //  another program.
+    pub fn for_stdout() -> FlushPolicy {
+        return if io::stdout().is_terminal() { FlushPolicy::Byte } else { FlushPolicy::Exit };
+    }
+}
+
+// Where a running program reads its input from and writes its output to. Interpreters only deal in bytes, so hosts can
+//  plug in anything that can produce and consume them, like sockets, GUIs or test harnesses.
+pub trait Io {
+    // Returns None once there's no more input.
+    fn read_byte(self: &mut Self) -> Option<u8>;
+    fn write_byte(self: &mut Self, byte: u8) -> ();
+    fn flush(self: &mut Self) -> ();
+
+    fn write_bytes(self: &mut Self, bytes: &[u8]) -> () {
+        for byte in bytes {
+            self.write_byte(*byte);
+        }
+    }
+
+    // Lets write! and writeln! be used with any Io, the same way they're used with a Write.
+    fn write_fmt(self: &mut Self, arguments: fmt::Arguments) -> fmt::Result {
+        self.write_bytes(arguments.to_string().as_bytes());
+        return Ok(());
+    }
+
+    // Reads up to and including the next newline. Returns None if there's no input left.
+    fn read_line(self: &mut Self) -> Option<String> {
+        let mut line = vec![];
+        while let Some(byte) = self.read_byte() {
+            line.push(byte);
+            if byte == b'\n' {
+                break;
+            }
+        }
+
+        return if line.is_empty() { None } else { Some(String::from_utf8_lossy(&line).to_string()) };
+    }
+
+    // Where the debugger reads its commands from and writes to. By default that's the program's own input and output.
+    fn console(self: &mut Self) -> &mut dyn Io where Self: Sized {
+        return self;
+    }
+}
+
+// Reads from and writes to any pair of streams. The writer is flushed according to the flush policy, so it should be
+//  buffered unless the policy is Byte.
+pub struct StreamIo<R: BufRead, W: Write> {
+    pub reader: R,
+    pub writer: W,
+    pub flush_policy: FlushPolicy,
+}
+
+pub type StdIo = StreamIo<Box<dyn BufRead>, BufferedStdout>;
+pub type FileIo = StreamIo<BufReader<File>, BufWriter<File>>;
+pub type TerminalIo = StreamIo<BufReader<File>, File>;
+
+impl<R: BufRead, W: Write> StreamIo<R, W> {
+    pub fn new(reader: R, writer: W, flush_policy: FlushPolicy) -> StreamIo<R, W> {
+        return StreamIo { reader, writer, flush_policy };
+    }
+}
+
+impl StdIo {
+    pub fn stdio(flush_policy: FlushPolicy) -> StdIo {
+        return StdIo::with_input(Box::new(io::stdin().lock()), flush_policy);
+    }
+
+    // Writes to stdout, but reads the program's input from somewhere else, like a file.
+    pub fn with_input(reader: Box<dyn BufRead>, flush_policy: FlushPolicy) -> StdIo {
+        return StreamIo::new(reader, BufferedStdout, flush_policy);
+    }
+}
+
+impl FileIo {
+    pub fn open(input_path: &Path, output_path: &Path) -> io::Result<FileIo> {
+        let reader = BufReader::new(File::open(input_path)?);
+        let writer = BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, File::create(output_path)?);
+        return Ok(StreamIo::new(reader, writer, FlushPolicy::Exit));
+    }
+}
+
+// Writes to a buffer in front of stdout. The buffer is shared, so that it can still be written out when exiting from the
+//  Ctrl+C handler's thread. Like a BufWriter, it's also written out when the writer is dropped.
+pub struct BufferedStdout;
+
+fn stdout_buffer() -> &'static Mutex<BufWriter<Stdout>> {
+    return STDOUT_BUFFER.get_or_init(|| Mutex::new(BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, io::stdout())));
+}
+
+impl Write for BufferedStdout {
+    fn write(self: &mut Self, bytes: &[u8]) -> io::Result<usize> {
+        return stdout_buffer().lock().expect("Locking stdout").write(bytes);
+    }
+
+    fn flush(self: &mut Self) -> io::Result<()> {
+        return stdout_buffer().lock().expect("Locking stdout").flush();
+    }
+}
+
+impl Drop for BufferedStdout {
+    fn drop(self: &mut Self) -> () {
+        flush_stdout_before_exit();
+    }
+}
+
+// Writes out whatever is buffered for stdout, unless it's in the middle of being written to, since this may be called
+//  from another thread right before the process exits.
+pub fn flush_stdout_before_exit() -> () {
+    if let Some(Ok(mut buffer)) = STDOUT_BUFFER.get().map(|buffer| buffer.try_lock()) {
+        let _ = buffer.flush();
+    }
+}
+
+// The controlling terminal, even when stdin and stdout are redirected. Returns None if there isn't one, like when
+//  running as a service or in CI.
+pub fn open_terminal() -> Option<TerminalIo> {
+    #[cfg(windows)]
+    let (input_path, output_path) = ("CONIN$", "CONOUT$");
+    #[cfg(not(windows))]
+    let (input_path, output_path) = ("/dev/tty", "/dev/tty");
+
+    let reader = BufReader::new(File::open(input_path).ok()?);
+    let writer = File::options().write(true).open(output_path).ok()?;
+    return Some(StreamIo::new(reader, writer, FlushPolicy::Byte));
+}
+
+impl<R: BufRead, W: Write> Io for StreamIo<R, W> {
+    fn read_byte(self: &mut Self) -> Option<u8> {
+        if self.flush_policy != FlushPolicy::Exit {
+            self.flush();
+        }
+
+        let byte = *self.reader.fill_buf().expect("Reading byte from input").first()?;
+        self.reader.consume(1);
+        return Some(byte);
+    }
+
+    fn write_byte(self: &mut Self, byte: u8) -> () {
+        self.write_bytes(&[byte]);
+    }
+
+    fn write_bytes(self: &mut Self, bytes: &[u8]) -> () {
+        self.writer.write_all(bytes).expect("Writing bytes to output");
+
+        let should_flush = match self.flush_policy {
+            FlushPolicy::Byte => true,
+            FlushPolicy::Line => bytes.contains(&b'\n'),
+            FlushPolicy::Input | FlushPolicy::Exit => false,
+        };
+        if should_flush {
+            self.flush();
+        }
+    }
+
+    fn flush(self: &mut Self) -> () {
+        self.writer.flush().expect("Flushing output");
+    }
+}
+
+// Reads from a slice and collects the output, for tests and for hosts that run programs on input they already have.
+pub struct MemoryIo<'a> {
+    // The input that hasn't been read yet.
+    pub input: &'a [u8],
+    pub output: Vec<u8>,
+}
+
+impl MemoryIo<'_> {
+    pub fn new(input: &[u8]) -> MemoryIo<'_> {
+        return MemoryIo { input, output: vec![] };
+    }
+}
+
+impl Io for MemoryIo<'_> {
+    fn read_byte(self: &mut Self) -> Option<u8> {
+        let (byte, rest) = self.input.split_first()?;
+        self.input = rest;
+        return Some(*byte);
+    }
+
+    fn write_byte(self: &mut Self, byte: u8) -> () {
+        self.output.push(byte);
+    }
+
+    fn flush(self: &mut Self) -> () {}
+}
+
+// Hands each byte to and from closures, for hosts whose I/O doesn't fit a stream.
+pub struct CallbackIo<R: FnMut() -> Option<u8>, W: FnMut(u8) -> ()> {
+    pub read: R,
+    pub write: W,
+}
+
+impl<R: FnMut() -> Option<u8>, W: FnMut(u8) -> ()> CallbackIo<R, W> {
+    pub fn new(read: R, write: W) -> CallbackIo<R, W> {
+        return CallbackIo { read, write };
+    }
+}
+
+impl<R: FnMut() -> Option<u8>, W: FnMut(u8) -> ()> Io for CallbackIo<R, W> {
+    fn read_byte(self: &mut Self) -> Option<u8> {
+        return (self.read)();
+    }
+
+    fn write_byte(self: &mut Self, byte: u8) -> () {
+        (self.write)(byte);
+    }
+
+    fn flush(self: &mut Self) -> () {}
+}
+
+// Runs a program on one Io while the debugger talks to the user through another, so that piping input into a program
+//  doesn't also feed it to the debugger's prompt.
+pub struct SeparateConsoleIo<P: Io, C: Io> {
+    pub program: P,
+    pub console: C,
+}
+
+impl<P: Io, C: Io> SeparateConsoleIo<P, C> {
+    pub fn new(program: P, console: C) -> SeparateConsoleIo<P, C> {
+        return SeparateConsoleIo { program, console };
+    }
+}
+
+impl<P: Io, C: Io> Io for SeparateConsoleIo<P, C> {
+    fn read_byte(self: &mut Self) -> Option<u8> {
+        return self.program.read_byte();
+    }
+
+    fn write_byte(self: &mut Self, byte: u8) -> () {
+        self.program.write_byte(byte);
+    }
+
+    fn write_bytes(self: &mut Self, bytes: &[u8]) -> () {
+        self.program.write_bytes(bytes);
+    }
+
+    fn flush(self: &mut Self) -> () {
+        self.program.flush();
+    }
+
+    fn console(self: &mut Self) -> &mut dyn Io where Self: Sized {
+        return &mut self.console;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn it_should_read_lines_and_format_into_memory() {
+        let mut io = MemoryIo::new(b"first\nsecond");
+        assert_eq!(io.read_line(), Some("first\n".to_string()));
+        assert_eq!(io.read_line(), Some("second".to_string()));
+        assert_eq!(io.read_line(), None);
+
+        writeln!(io, "{} + {}", 1, 2).unwrap();
+        assert_eq!(io.output, b"1 + 2\n");
+    }
+
+    #[test]
+    fn it_should_only_flush_when_the_policy_says_to() {
+        let mut line_io = StreamIo::new(&b"x"[..], BufWriter::new(vec![]), FlushPolicy::Line);
+        line_io.write_bytes(b"ab");
+        assert!(line_io.writer.get_ref().is_empty());
+        line_io.write_byte(b'\n');
+        assert_eq!(line_io.writer.get_ref(), b"ab\n");
+
+        let mut input_io = StreamIo::new(&b"x"[..], BufWriter::new(vec![]), FlushPolicy::Input);
+        input_io.write_bytes(b"Name? ");
+        assert!(input_io.writer.get_ref().is_empty());
+        input_io.read_byte();
+        assert_eq!(input_io.writer.get_ref(), b"Name? ");
+
+        let mut exit_io = StreamIo::new(&b"x"[..], BufWriter::new(vec![]), FlushPolicy::Exit);
+        exit_io.write_bytes(b"a\n");
+        exit_io.read_byte();
+        assert!(exit_io.writer.get_ref().is_empty());
+    }
+
+    #[test]
+    fn it_should_pass_bytes_to_callbacks() {
+        let mut input = vec![b'b', b'a'];
+        let mut output = vec![];
+        {
+            let mut io = CallbackIo::new(|| input.pop(), |byte| output.push(byte));
+            while let Some(byte) = io.read_byte() {
+                io.write_byte(byte.to_ascii_uppercase());
+            }
+        }
+
+        assert_eq!(output, b"AB");
+    }
+
+    #[test]
+    fn it_should_read_and_write_files() {
+        let dir = env::temp_dir().join(format!("ezfuck_file_io_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("input"), "hi").unwrap();
+
+        let mut io = FileIo::open(&dir.join("input"), &dir.join("output")).unwrap();
+        while let Some(byte) = io.read_byte() {
+            io.write_byte(byte);
+        }
+        io.flush();
+
+        assert_eq!(fs::read_to_string(dir.join("output")).unwrap(), "hi");
+    }
+
+    #[test]
+    fn it_should_keep_the_console_apart_from_the_program() {
+        let mut io = SeparateConsoleIo::new(MemoryIo::new(b"program"), MemoryIo::new(b"command\n"));
+        assert_eq!(io.console().read_line(), Some("command\n".to_string()));
+        assert_eq!(io.read_byte(), Some(b'p'));
+
+        writeln!(io.console(), "EZ>").unwrap();
+        io.write_byte(b'!');
+        assert_eq!(io.program.output, b"!");
+        assert_eq!(io.console.output, b"EZ>\n");
+    }
+}
@@ -1,5 +1,4 @@
-use std::io;
-use std::io::{BufRead, Read, Write};
+use crate::program_io::program_io::{FlushPolicy, Io, StdIo};
 use crate::standard_brainfuck::parser::parser::{Instruction, EqualityOperator};
 
 fn ensure_cell(cells: &mut Vec<u8>, slot_i: usize) -> () {
@@ -22,18 +21,15 @@ fn add_cell_ptr_value(current_cell_ptr: usize, ptr_offset: isize) -> usize {
     };
 }
 
-fn print_value<W: Write>(out_stream: &mut W, cell: u8) {
-    write!(out_stream, "{}", char::from(cell)).unwrap();
-    io::stdout().flush().unwrap();
+fn print_value<I: Io>(io: &mut I, cell: u8) {
+    io.write_byte(cell);
 }
 
-fn read_value<R: BufRead>(in_stream: &mut R) -> u8 {
-    let mut input = [0; 1];
-    in_stream.read_exact(&mut input).expect("Reading byte from stdin");
-    return input[0];
+fn read_value<I: Io>(io: &mut I) -> u8 {
+    return io.read_byte().expect("Reading byte from stdin");
 }
 
-pub fn interpret<R: BufRead, W: Write>(instructions: &Vec<Instruction>, in_stream: &mut R, out_stream: &mut W) {
+pub fn interpret<I: Io>(instructions: &Vec<Instruction>, io: &mut I) {
     let mut instruction_ptr = 0;
     let mut cell_ptr = 0;
 
@@ -70,11 +66,11 @@ pub fn interpret<R: BufRead, W: Write>(instructions: &Vec<Instruction>, in_strea
             }
 
             Instruction::PrintOut => {
-                print_value(out_stream, cells[cell_ptr]);
+                print_value(io, cells[cell_ptr]);
             }
 
             Instruction::ReadIn => {
-                let input = read_value(in_stream);
+                let input = read_value(io);
                 cells[cell_ptr] = input;
             }
         }
@@ -82,19 +78,17 @@ pub fn interpret<R: BufRead, W: Write>(instructions: &Vec<Instruction>, in_strea
         instruction_ptr += 1;
         // println!("Cell Ptr: {cell_ptr}, Inst Ptr: {instruction_ptr} Cells: {cells:?}");
     }
+
+    io.flush();
 }
 
 pub fn interpret_with_std_io(instructions: &Vec<Instruction>) {
-    let stdin = io::stdin();
-    let mut input = stdin.lock();
-
-    let mut stdout = io::stdout();
-
-    interpret(instructions, &mut input, &mut stdout);
+    interpret(instructions, &mut StdIo::stdio(FlushPolicy::for_stdout()));
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::program_io::program_io::MemoryIo;
     use crate::standard_brainfuck::parser::parser::parse;
     use super::*;
 
@@ -104,11 +98,10 @@ mod tests {
         let code = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
         let instructions = parse(code);
 
-        let mut input = &b""[..];
-        let mut output = vec![];
-        interpret(&instructions, &mut input, &mut output);
+        let mut io = MemoryIo::new(b"");
+        interpret(&instructions, &mut io);
 
-        let output_string = String::from_utf8(output).unwrap();
+        let output_string = String::from_utf8(io.output).unwrap();
         assert_eq!(output_string, "Hello World!\n");
     }
 }
\ No newline at end of file